@@ -5,11 +5,10 @@ ct_python! {
 }
 
 static DIRECTIONS: [(f64, f64); 4] = ct_python! {
-    from math import sin, cos, tau
-    n = 4
+    include("helpers.py")
     print("[")
-    for i in range(n):
-        print("(", cos(i / n * tau), ",", sin(i / n * tau), "),")
+    for x, y in directions(4):
+        print("(", x, ",", y, "),")
     print("]")
 };
 
@@ -0,0 +1,5 @@
+from math import sin, cos, tau
+
+
+def directions(n):
+    return [(cos(i / n * tau), sin(i / n * tau)) for i in range(n)]
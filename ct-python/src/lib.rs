@@ -26,21 +26,401 @@
 //! The output of the script (`print()` and anything else through `sys.stdout`)
 //! is captured, and will be parsed and injected as Rust code.
 //!
+//! ## Debugging output
+//!
+//! To keep `print()` available for debugging, use `emit()` to produce the Rust code.
+//! It takes the same arguments as `print()`.
+//! Once the code calls `emit()`, only the emitted code is used as output,
+//! and anything printed is shown during compilation, like stderr.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static ANSWER: i32 = ct_python! {
+//!     answer = 6 * 7
+//!     print("computed the answer:", answer)
+//!     emit(answer)
+//! };
+//! # assert_eq!(ANSWER, 42);
+//! ```
+//!
+//! ## String and byte string literals
+//!
+//! If you only need a single string or byte string, use
+//! [`ct_python_str!{..}`][ct_python_str] or [`ct_python_bytes!{..}`][ct_python_bytes].
+//! These evaluate the final expression of the Python code and turn its value
+//! into a Rust literal directly, without parsing any output as Rust code.
+//!
+//! ```
+//! # use ct_python::ct_python_str;
+//! static ALPHABET: &str = ct_python_str! {
+//!     import string
+//!     string.ascii_lowercase
+//! };
+//! # assert_eq!(ALPHABET, "abcdefghijklmnopqrstuvwxyz");
+//! ```
+//!
+//! ## Generating a module
+//!
+//! To place the generated items in a new module, rather than where the macro is used,
+//! use [`ct_python_mod!{name, ..}`][ct_python_mod] with the module name (and optionally a visibility)
+//! before the Python code.
+//!
+//! ```
+//! # use ct_python::ct_python_mod;
+//! ct_python_mod! { pub tables,
+//!     print("pub static SQUARES: [u8; 10] =", [i * i for i in range(10)], ";")
+//! }
+//! # fn main() { assert_eq!(tables::SQUARES[9], 81); }
+//! ```
+//!
+//! Like the items of any module, the generated code has to refer to items outside of it
+//! through `super::` (or a `use` statement that it prints).
+//! This also works with `//! output: file`, in which case the module contains the `include!{}`.
+//!
+//! ## Float literals
+//!
+//! Printing a Python `float` gives `inf` or `nan` for the special values, which are not valid Rust.
+//! Use `rust_float(x)` to format a number as a Rust `f64` literal instead,
+//! which gives `f64::INFINITY`, `f64::NEG_INFINITY`, or `f64::NAN` for those.
+//! Other values are formatted with the shortest representation that gives back exactly the same `f64`,
+//! with an `f64` suffix. `rust_float(x, "f32")` does the same for `f32`.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static VALUES: [f64; 4] = ct_python! {
+//!     print("[", ", ".join(rust_float(x) for x in [0.1, 1e308 * 10, -1e308 * 10, 2]), "]")
+//! };
+//! # assert_eq!(VALUES, [0.1, f64::INFINITY, f64::NEG_INFINITY, 2.0]);
+//! static NAN: f32 = ct_python! {
+//!     print(rust_float(float("nan"), "f32"))
+//! };
+//! # assert!(NAN.is_nan());
+//! ```
+//!
+//! Similarly, `rust_complex(z)` formats a Python `complex` (or any number) as a
+//! `::num_complex::Complex64::new(re, im)` expression, with both parts formatted by `rust_float`.
+//! `rust_complex(z, "f32")` gives a `Complex32` instead.
+//! The crate using the output needs to depend on `num-complex` itself.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static ROOT: num_complex::Complex64 = ct_python! {
+//!     import cmath
+//!     print(rust_complex(cmath.sqrt(-4)))
+//! };
+//! # assert_eq!(ROOT, num_complex::Complex64::new(0.0, 2.0));
+//! ```
+//!
+//! ## Rust types
+//!
+//! `rust_type_of(value)` gives a best-effort Rust type for a Python value,
+//! for generating declarations that match the generated values:
+//! `bool`, `i64` (or `i128` or `u128` for integers that don't fit), `f64`,
+//! `::num_complex::Complex64`, `&str`, `&[u8]`, and `()` for `None`.
+//! Lists, sets and dicts give a `Vec`, `HashSet` or `HashMap` of their element type,
+//! and tuples give a tuple of the types of their elements.
+//! A `TypeError` is raised for other types, for a collection with elements of different types,
+//! and a `ValueError` for an empty collection, since its element type can't be known,
+//! or an integer that doesn't fit in a `u128` or `i128`.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! ct_python! {
+//!     for name, value in [("ANSWER", 42), ("SCALE", 0.5), ("POINT", (1, 2.5))]:
+//!         print("const %s: %s = %r;" % (name, rust_type_of(value), value))
+//! }
+//! # assert_eq!((ANSWER, SCALE, POINT), (42i64, 0.5f64, (1i64, 2.5f64)));
+//! ```
+//!
+//! ## Cargo environment variables
+//!
+//! The `CARGO_*` and `OUT_DIR` environment variables that Cargo sets while
+//! compiling your crate are available to the Python code through `os.environ`.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static NAME: &str = ct_python! {
+//!     import os
+//!     print("\"" + os.environ["CARGO_PKG_NAME"] + "\"")
+//! };
+//! # assert_eq!(NAME, env!("CARGO_PKG_NAME"));
+//! ```
+//!
+//! ## Call site
+//!
+//! The location of the `ct_python!{}` invocation is available as the `call_site` dictionary,
+//! with the `file` (as shown by the compiler), `line`, and `column` (starting at 1), and the
+//! name of the `crate` that is being compiled.
+//! This can be used to add a comment about where generated code came from, for example.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static LINE: u32 = ct_python! {
+//!     print(call_site["line"])
+//! };
+//! # assert_eq!(LINE, line!() - 3);
+//! # static A: u32 = ct_python! { print(globals()["call" + "_site"]["line"]) };
+//! # static B: u32 = ct_python! { print(globals()["call" + "_site"]["line"]) };
+//! # assert_eq!(B, A + 1);
+//! ```
+//!
+//! Code that uses `call_site` is not [cached](#caching) between different invocations.
+//! This is also the case for code that could use it indirectly, through
+//! `globals()`, `vars()`, `locals()`, `eval()`, `exec()`, or an included file.
+//! (Other modules can only get to it through frame inspection, like `sys._getframe()`.
+//! Use `//! cache: false` if an imported module does that.)
+//!
+//! ## Including Python files
+//!
+//! To keep larger scripts maintainable, the Python code can call `include("file.py")`
+//! to run a Python file in the same namespace, like `exec(open("file.py").read())`.
+//! Relative paths are resolved relative to the directory of the Rust source file.
+//!
+//! ```ignore
+//! ct_python! {
+//!     include("helpers.py") // Defines `make_table`.
+//!     print(make_table())
+//! }
+//! ```
+//!
+//! The files read through `include()` and `open()` are tracked by the compiler,
+//! so changing them causes your crate to be recompiled.
+//! (The macro adds a `const _: &[u8] = include_bytes!(..);` for each of them to the generated code.
+//! If the generated code is an expression, including a literal from `ct_python_str!{}`
+//! or `ct_python_bytes!{}`, it is put in a block after those, so it is no longer a literal.)
+//! Only direct calls to `open()` in the script are seen, not files opened by other modules.
+//! For those, let Cargo know about them in a build script:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     println!("cargo::rerun-if-changed=data/table.csv");
+//! }
+//! ```
+//!
+//! ## Importing local modules
+//!
+//! The root directory of your crate (`CARGO_MANIFEST_DIR`) and its `python`
+//! subdirectory are added to the start of `sys.path` while the code runs,
+//! such that `import mymodule` finds `mymodule.py` (or `python/mymodule.py`) in your crate.
+//! A `//! sys_path: dir` line uses `dir` instead of `python`,
+//! and `//! sys_path: false` disables this entirely.
+//!
+//! ```ignore
+//! ct_python! {
+//!     //! sys_path: codegen
+//!     from tables import generate // Imports codegen/tables.py.
+//!     print(generate())
+//! }
+//! ```
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static CRATE_ROOT_IN_PATH: bool = ct_python! {
+//!     import os, sys
+//!     print(str(sys.path[0] == os.environ["CARGO_MANIFEST_DIR"]).lower())
+//! };
+//! # assert!(CRATE_ROOT_IN_PATH);
+//! ```
+//!
+//! Unlike included files, imported modules are not tracked, so Cargo does not know about them.
+//!
+//! ## Exporting modules
+//!
+//! A `//! export: name` line puts the global variables, functions and classes defined
+//! by the code in `sys.modules` as a module called `name` after it ran successfully,
+//! such that later `ct_python!{}` invocations in the same crate can `import name`.
+//! Exporting a module again (e.g. from a newer version of the same code) replaces it,
+//! but exporting a module with the name of any other module fails.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! ct_python! {
+//!     //! export: shapes
+//!     def square(n):
+//!         return n * n
+//! }
+//!
+//! static AREA: u32 = ct_python! {
+//!     from shapes import square
+//!     print(square(7))
+//! };
+//! # assert_eq!(AREA, 49);
+//! ```
+//!
+//! This relies on all invocations running in the same Python interpreter,
+//! which is only the case within a single crate, and only works if the exporting
+//! invocation is expanded first.
+//! Rust does not guarantee any order of macro expansion.
+//! In practice, invocations are expanded in the order they appear in a file,
+//! and files in the order of their `mod` declarations,
+//! but tools like rust-analyzer might expand a single file (or invocation) on its own.
+//! For helpers used by multiple files, a Python file [imported from the crate](#importing-local-modules)
+//! is more reliable.
+//!
+//! Since the module only exists while the crate is compiled, `python!{}` blocks,
+//! which run when the program runs, can't import it.
+//!
+//! ## Virtual environments
+//!
+//! If the `VIRTUAL_ENV` environment variable is set while compiling (e.g. by
+//! activating a virtual environment before running Cargo), the packages
+//! installed in that virtual environment can be imported by the Python code.
+//!
+//! Note that this does not change the Python interpreter itself:
+//! that is always the Python library that `ct-python` was linked against
+//! (selected by PyO3 when building, e.g. through the `PYO3_PYTHON` environment variable).
+//! The virtual environment must be made with the same Python version,
+//! as the extension modules it contains are built for that version.
+//! (On Unix, the Python library is loaded with global symbols before running the code,
+//! such that those extension modules can find the Python symbols they need.
+//! This requires a shared libpython, as does Windows, where extension modules find
+//! the Python symbols in the already loaded `pythonXY.dll`.
+//! If that is not available, import errors include a note explaining why.)
+//!
+//! Cargo does not track `VIRTUAL_ENV`, so changing it does not cause a recompilation.
+//!
+//! ## Large output
+//!
+//! With a `//! output: file` line, the output is written directly to a file in `OUT_DIR`,
+//! rather than collected in memory and parsed by the macro.
+//! The macro then expands to an `include!{}` of that file.
+//! This can be faster for scripts that generate a lot of code.
+//! `OUT_DIR` is only set for crates that have a build script, so this requires one (which may be empty).
+//!
+//! ```ignore
+//! ct_python! {
+//!     //! output: file
+//!     for i in range(100000):
+//!         print("pub const C%d: u32 = %d;" % (i, i))
+//! }
+//! ```
+//!
+//! To keep a buggy script from exhausting memory during the build, the output
+//! that is collected in memory is limited to 16 MiB. Writing more than that fails the compilation,
+//! with an error pointing at the line that wrote past the limit.
+//! The limit can be changed with the `INLINE_PYTHON_MAX_OUTPUT_BYTES` environment variable.
+//! Output written to a file with `//! output: file` is not limited.
+//!
+//! ## Caching
+//!
+//! If the exact same Python code appears in multiple `ct_python!{}` invocations,
+//! it is only executed once per compilation, and the generated code is reused.
+//! If your Python code has side effects or otherwise produces different results
+//! every time, disable this with a `//! cache: false` line at the start of the
+//! Python code:
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! ct_python! {
+//!     //! cache: false
+//!     import random
+//!     print("static RANDOM: u32 =", random.randrange(1000), ";")
+//! }
+//! # fn main() { assert!(RANDOM < 1000); }
+//! ```
+//!
+//! ## Failing the build
+//!
+//! The Python code can call `fail(message)` to fail the compilation with the given message.
+//! The error points at the line that called `fail`, without a traceback.
+//! This can be used to check requirements of the build environment:
+//!
+//! ```compile_fail
+//! # use ct_python::ct_python;
+//! ct_python! {
+//!     import sys
+//!     if sys.version_info < (4, 0):
+//!         fail("this crate requires Python 4")
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! ## Timeout
+//!
+//! To keep a buggy script (e.g. with an infinite loop) from hanging the build forever,
+//! a `//! timeout: 10` line limits how many seconds the code may run.
+//! A default timeout for all `ct_python!{}` invocations can be set with the
+//! `INLINE_PYTHON_TIMEOUT_SECS` environment variable.
+//! When the time runs out, a `TimeoutError` is raised in the Python code,
+//! and reported as a compiler error pointing at the line that was running.
+//!
+//! Note that the exception only interrupts Python code. A single long running call into native code,
+//! like `time.sleep()`, is only interrupted once it returns.
+//!
+//! ## Profiling
+//!
+//! With the `INLINE_PYTHON_PROFILE=1` environment variable set while compiling,
+//! a note with the time it took to compile and to run the Python code is printed for every `ct_python!{}` invocation.
+//! This shows which scripts take up most of the build time.
+//!
 //! ## Python Errors
 //!
 //! Any syntax errors and runtime exceptions from the Python code will be
 //! reported by the Rust compiler as compiler errors.
 //!
+//! Anything the Python code writes to `sys.stderr` is included in the error
+//! message. If the Python code runs successfully, its stderr output is simply
+//! forwarded to the compiler's stderr.
+//!
+//! If the output is not valid Rust code, the error shows the line of the output
+//! that contains the problem (like an unclosed delimiter or an unterminated string), with its line number.
+//!
+//! Python warnings (like a `SyntaxWarning` or `DeprecationWarning`) while compiling or running the code
+//! are printed as `warning: python: ..` lines, together with the line that caused them.
+//!
+//! ## Compilation mode
+//!
+//! A `//! mode: single` or `//! mode: eval` line at the start of the Python
+//! code selects a different compilation mode.
+//! See [the `inline-python` documentation][2] for details.
+//!
+//! ```
+//! # use ct_python::ct_python;
+//! static SEVEN: i32 = ct_python! {
+//!     //! mode: single
+//!     3 + 4
+//! };
+//! # assert_eq!(SEVEN, 7);
+//! ```
+//!
+//! Similarly, a `//! module: name` line sets `__name__` and the file name
+//! used in tracebacks. See [the `inline-python` documentation][3].
+//! A `//! seed: 1234` line seeds the `random` module, to make the output reproducible.
+//! `assert` statements are always checked, unless an `//! optimize: 1` line is used.
+//! See [the `inline-python` documentation][4].
+//!
 //! ## Syntax issues
 //!
 //! Since the Rust tokenizer will tokenize the Python code, some valid Python
 //! code is rejected. See [the `inline-python` documentation][1] for details.
 //!
 //! [1]: https://docs.rs/inline-python/#syntax-issues
+//! [2]: https://docs.rs/inline-python/#compilation-mode
+//! [3]: https://docs.rs/inline-python/#module-name
+//! [4]: https://docs.rs/inline-python/#optimization-level
 
-use proc_macro::{Span, TokenStream};
-use pyo3::{PyObject, PyResult, Python, prelude::*};
-use std::{ffi::CString, ptr::null_mut, str::FromStr};
+use proc_macro::{Delimiter, Ident, Literal, Span, TokenStream, TokenTree};
+use pyo3::{
+    PyObject, PyResult, Python,
+    exceptions::{PyImportError, PyTimeoutError},
+    prelude::*,
+    types::{IntoPyDict, PyDict, PyFrozenSet, PyList},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{CStr, CString, c_long},
+    fmt::Write,
+    path::{Path, PathBuf},
+    ptr::null_mut,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
 
 mod shared;
 use shared::*;
@@ -50,47 +430,1084 @@ use shared::*;
 /// See [the crate's module level documentation](index.html) for examples.
 #[proc_macro]
 pub fn ct_python(input: TokenStream) -> TokenStream {
-    ct_python_impl(input).unwrap_or_else(|e| e)
+    ct_python_impl(input, Output::Tokens).unwrap_or_else(|e| e)
+}
+
+/// A block of compile-time executed Python code that evaluates to a string literal.
+///
+/// The Python code must end in an expression. That expression is converted
+/// to a string using `str()` and turned into a Rust string literal verbatim.
+///
+/// ```
+/// # use ct_python::ct_python_str;
+/// static GREETING: &str = ct_python_str! {
+///     " ".join(["Hello", "world!"])
+/// };
+/// # assert_eq!(GREETING, "Hello world!");
+/// ```
+#[proc_macro]
+pub fn ct_python_str(input: TokenStream) -> TokenStream {
+    ct_python_impl(input, Output::Str).unwrap_or_else(|e| e)
+}
+
+/// A block of compile-time executed Python code that evaluates to a byte string literal.
+///
+/// The Python code must end in an expression that evaluates to `bytes`
+/// (or anything else that can be converted to a sequence of bytes).
+/// It is turned into a Rust byte string literal verbatim.
+///
+/// ```
+/// # use ct_python::ct_python_bytes;
+/// static DATA: &[u8] = ct_python_bytes! {
+///     bytes(range(4))
+/// };
+/// # assert_eq!(DATA, [0, 1, 2, 3]);
+/// ```
+#[proc_macro]
+pub fn ct_python_bytes(input: TokenStream) -> TokenStream {
+    ct_python_impl(input, Output::Bytes).unwrap_or_else(|e| e)
 }
 
-fn ct_python_impl(input: TokenStream) -> Result<TokenStream, TokenStream> {
-    let python = CString::new(python_from_macro(input.clone(), None)?).unwrap();
-    let filename = CString::new(Span::call_site().file()).unwrap();
+/// A block of compile-time executed Python code generating the items of a new module.
+///
+/// This works like [`ct_python!{..}`][ct_python], but the generated code is placed in
+/// a module with the name (and optional visibility) before the first comma,
+/// rather than directly where the macro is used.
+///
+/// ```
+/// # use ct_python::ct_python_mod;
+/// ct_python_mod! { pub(crate) powers,
+///     for i in range(4):
+///         print("pub const POW_%d: u32 = %d;" % (i, 2 ** i))
+/// }
+///
+/// # fn main() {
+/// assert_eq!(powers::POW_3, 8);
+/// # }
+/// ```
+#[proc_macro]
+pub fn ct_python_mod(input: TokenStream) -> TokenStream {
+    ct_python_mod_impl(input).unwrap_or_else(|e| e)
+}
 
+fn ct_python_mod_impl(input: TokenStream) -> Result<TokenStream, TokenStream> {
+    let mut tokens = input.into_iter();
+    let mut header = Vec::new();
+    let error = |span: Option<Span>| {
+        let spans = span.map(|span| (span, span));
+        compile_error(
+            spans,
+            "expected a module name and a comma, like `ct_python_mod! { name, .. }`",
+        )
+    };
+    if let Some(TokenTree::Ident(first)) = tokens.clone().next()
+        && first.to_string() == "pub"
+    {
+        header.push(tokens.next().unwrap());
+        if let Some(TokenTree::Group(group)) = tokens.clone().next()
+            && group.delimiter() == Delimiter::Parenthesis
+        {
+            header.push(tokens.next().unwrap());
+        }
+    }
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(name)) => name,
+        other => return Err(error(other.map(|t| t.span()))),
+    };
+    match tokens.next() {
+        Some(TokenTree::Punct(comma)) if comma.as_char() == ',' => {}
+        other => return Err(error(Some(other.map_or(name.span(), |t| t.span())))),
+    }
+    let items = ct_python_impl(tokens.collect(), Output::Tokens)?;
+    let module = Ident::new("mod", name.span());
+    header.extend([
+        TokenTree::Ident(module),
+        TokenTree::Ident(name),
+        braces(items),
+    ]);
+    Ok(TokenStream::from_iter(header))
+}
+
+/// Defines `uses_call_site(source)`, which compiles the code (ignoring warnings),
+/// and checks whether it (or any nested function or class) refers to any of [`CALL_SITE_NAMES`].
+/// Code that does not compile is assumed to use them.
+const USES_CALL_SITE: &CStr = cr#"
+def uses_call_site(source, names):
+    import warnings
+    try:
+        with warnings.catch_warnings():
+            warnings.simplefilter("ignore")
+            code = compile(source, "<ct_python>", "exec")
+    except Exception:
+        return True
+    codes = [code]
+    while codes:
+        code = codes.pop()
+        if not names.isdisjoint(code.co_names):
+            return True
+        codes.extend(c for c in code.co_consts if hasattr(c, "co_names"))
+    return False
+"#;
+
+/// The names through which the code can get to `call_site`.
+const CALL_SITE_NAMES: [&str; 7] = [
+    "call_site",
+    "globals",
+    "vars",
+    "locals",
+    "eval",
+    "exec",
+    "include",
+];
+
+/// Whether the result of the code might depend on `call_site`, such that it can't be reused elsewhere.
+fn uses_call_site(python: &str) -> bool {
     Python::with_gil(|py| {
-        let code = compile_python(py, &python, &filename, input.clone())?;
-        let output = run_and_capture(py, code)
-            .map_err(|err| python_error_to_compile_error(py, err, input))?;
-        TokenStream::from_str(&output)
-            .map_err(|_| compile_error(None, "produced invalid Rust code"))
+        let check = || -> PyResult<bool> {
+            let namespace = PyDict::new(py);
+            py.run(USES_CALL_SITE, Some(&namespace), None)?;
+            let names = PyFrozenSet::new(py, CALL_SITE_NAMES)?;
+            let uses = namespace.get_item("uses_call_site")?.unwrap();
+            uses.call1((python, names))?.is_truthy()
+        };
+        check().unwrap_or(true)
     })
 }
 
-fn run_and_capture(py: Python, code: PyObject) -> PyResult<String> {
-    #[cfg(unix)]
-    let _ = ensure_libpython_symbols_loaded(py);
+/// What a `ct_python` macro expands to.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Output {
+    /// The captured stdout, parsed as Rust code.
+    Tokens,
+    /// The value of the final expression, as a string literal.
+    Str,
+    /// The value of the final expression, as a byte string literal.
+    Bytes,
+    /// The captured stdout, written to a file in `OUT_DIR`, which is included with `include!{}`.
+    File,
+}
+
+/// The output kind, the directives that affect the result, the call site (if used), and the Python code.
+type CacheKey = (
+    Output,
+    Mode,
+    Option<String>,
+    Option<u64>,
+    Option<u8>,
+    Option<String>,
+    Option<String>,
+    Option<(String, usize, usize)>,
+    String,
+);
+
+/// The Rust code generated by earlier invocations.
+static CACHE: Mutex<BTreeMap<CacheKey, Generated>> = Mutex::new(BTreeMap::new());
+
+/// The Rust code generated by the Python code, and the files it read (through `include` and `open`).
+#[derive(Clone)]
+struct Generated {
+    code: String,
+    files: Vec<String>,
+}
+
+fn ct_python_impl(input: TokenStream, output: Output) -> Result<TokenStream, TokenStream> {
+    let (directives, input) = parse_directives(input)?;
+    let output = match (output, directives.output_file) {
+        (Output::Tokens, true) if std::env::var_os("OUT_DIR").is_none() => {
+            return Err(compile_error(
+                None,
+                "`//! output: file` requires `OUT_DIR`, which Cargo only sets for crates with a build script",
+            ));
+        }
+        (Output::Tokens, true) => Output::File,
+        (_, true) => {
+            return Err(compile_error(
+                None,
+                "`//! output: file` is only supported by `ct_python!{}`",
+            ));
+        }
+        (output, false) => output,
+    };
+    let (python, lines) = python_from_macro(input, None)?;
+    dump_python(&python);
+
+    // The leading newlines only serve to get the line numbers right.
+    let key = (
+        output,
+        directives.mode,
+        directives.module.clone(),
+        directives.seed,
+        directives.optimize,
+        directives.sys_path.clone(),
+        directives.export.clone(),
+        uses_call_site(&python).then(|| {
+            let site = Span::call_site();
+            (site.file(), site.line(), site.column())
+        }),
+        python.trim_start_matches('\n').to_string(),
+    );
+    let cached = directives
+        .cache
+        .then(|| CACHE.lock().unwrap().get(&key).cloned());
+    let Generated {
+        code: rust_code,
+        files,
+    } = match cached.flatten() {
+        Some(generated) => generated,
+        None => {
+            let generated = run_python(&lines, &python, &directives, output)?;
+            if directives.cache {
+                CACHE.lock().unwrap().insert(key, generated.clone());
+            }
+            generated
+        }
+    };
+
+    // Some lexer errors make `TokenStream::from_str` panic instead of returning an error
+    // (or report an error without pointing anywhere useful), so check for them first.
+    if let Some((offset, reason)) = find_lex_error(&rust_code) {
+        let msg = format!(
+            "produced invalid Rust code: {reason}\n{}",
+            show_output_line(&rust_code, offset)
+        );
+        return Err(compile_error(None, &msg));
+    }
+
+    let tokens = TokenStream::from_str(&rust_code).map_err(|err| {
+        // Find the item that failed to parse, to show that one instead of the start of the output.
+        let items = split_items(&rust_code);
+        let failed = items
+            .iter()
+            .enumerate()
+            .find_map(|(i, item)| Some((i, item, TokenStream::from_str(item).err()?)));
+        let msg = match failed {
+            Some((i, item, err)) if items.len() > 1 => format!(
+                "produced invalid Rust code in item {} of {} ({err}): {}",
+                i + 1,
+                items.len(),
+                snippet(item)
+            ),
+            _ => format!(
+                "produced invalid Rust code ({err}): {}",
+                snippet(&rust_code)
+            ),
+        };
+        compile_error(None, &msg)
+    })?;
+    Ok(track_files(tokens, &files, output))
+}
+
+/// Make rustc (and thereby Cargo) track the files that the Python code read,
+/// by adding an `include_bytes!()` of every one of them to the generated code,
+/// such that the crate is recompiled when they change.
+///
+/// When the generated code is not a list of items (or statements), it is assumed
+/// to be an expression and put in a block after the `include_bytes!()`s.
+fn track_files(tokens: TokenStream, files: &[String], output: Output) -> TokenStream {
+    let files: BTreeSet<&String> = files.iter().filter(|f| Path::new(f).is_file()).collect();
+    if files.is_empty() {
+        return tokens;
+    }
+    let includes = files
+        .iter()
+        .map(|file| {
+            let path = Literal::string(file);
+            TokenStream::from_str(&format!("const _: &[u8] = ::core::include_bytes!({path});"))
+                .unwrap()
+        })
+        .collect::<TokenStream>();
+    let items = match output {
+        Output::Str | Output::Bytes => false,
+        Output::Tokens => looks_like_items(&tokens),
+        // The code itself is in the included file.
+        Output::File => std::fs::read_to_string(output_file_path())
+            .ok()
+            .and_then(|code| TokenStream::from_str(&code).ok())
+            .is_none_or(|tokens| looks_like_items(&tokens)),
+    };
+    if items {
+        includes.into_iter().chain(tokens).collect()
+    } else {
+        TokenStream::from_iter([braces(includes.into_iter().chain(tokens))])
+    }
+}
+
+/// Whether the code looks like items or statements, rather than a single expression:
+/// it's empty, ends in a `;`, or starts with an attribute or a keyword that starts an item.
+fn looks_like_items(tokens: &TokenStream) -> bool {
+    const ITEM_KEYWORDS: &[&str] = &[
+        "pub",
+        "fn",
+        "struct",
+        "enum",
+        "union",
+        "impl",
+        "trait",
+        "type",
+        "static",
+        "mod",
+        "use",
+        "extern",
+        "macro_rules",
+        "let",
+    ];
+    let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    match (tokens.first(), tokens.get(1), tokens.last()) {
+        (None, _, _) => true,
+        (_, _, Some(TokenTree::Punct(p))) if p.as_char() == ';' => true,
+        (Some(TokenTree::Punct(p)), _, _) => p.as_char() == '#',
+        // `const` and `unsafe` can also start a block expression.
+        (Some(TokenTree::Ident(i)), Some(TokenTree::Ident(_)), _)
+            if matches!(&*i.to_string(), "const" | "unsafe") =>
+        {
+            true
+        }
+        (Some(TokenTree::Ident(i)), _, _) => ITEM_KEYWORDS.contains(&&*i.to_string()),
+        _ => false,
+    }
+}
+
+/// Split Rust code into its top-level items, at every `;` or `}` outside of any brackets.
+///
+/// This only roughly tokenizes the code, skipping over comments, strings, and character literals,
+/// which is good enough to point at the item that contains an error.
+fn split_items(code: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    let mut chars = code.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let rest = &code[i..];
+        match c {
+            '/' if rest.starts_with("//") => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '/' if rest.starts_with("/*") => {
+                let end = rest.find("*/").map_or(code.len(), |end| i + end + 2);
+                while chars.next_if(|&(j, _)| j < end).is_some() {}
+            }
+            'r' if rest.starts_with("r\"") || rest.starts_with("r#") => {
+                let hashes = rest[1..].len() - rest[1..].trim_start_matches('#').len();
+                if rest[1 + hashes..].starts_with('"') {
+                    let close = format!("\"{}", "#".repeat(hashes));
+                    let body = i + 2 + hashes;
+                    let end = code[body..]
+                        .find(&close)
+                        .map_or(code.len(), |end| body + end + close.len());
+                    while chars.next_if(|&(j, _)| j < end).is_some() {}
+                }
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => _ = chars.next(),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            // A character literal, not a lifetime.
+            '\'' if rest[1..].starts_with('\\') || rest[1..].chars().nth(1) == Some('\'') => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => _ = chars.next(),
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '}' | ';' => {
+                if c == '}' {
+                    depth -= 1;
+                }
+                if depth <= 0 {
+                    depth = 0;
+                    items.push(code[start..i + 1].trim());
+                    start = i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    if !code[start..].trim().is_empty() {
+        items.push(code[start..].trim());
+    }
+    items
+}
+
+/// Find the first problem that would make the Rust code fail to tokenize,
+/// and return its byte offset and a description.
+///
+/// This recognizes unbalanced or mismatched delimiters, unterminated strings, character literals and
+/// block comments, and characters that can't start a token.
+/// Anything else is left to the real tokenizer.
+fn find_lex_error(code: &str) -> Option<(usize, String)> {
+    let line_of = |offset: usize| code[..offset].matches('\n').count() + 1;
+    let is_ident = |c: char| c == '_' || c.is_alphanumeric();
+    // The offset just past the closing quote of a (non-raw) string or character literal starting at `start`.
+    let literal_end = |start: usize, quote: char| {
+        let mut chars = code[start + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => _ = chars.next(),
+                '\n' if quote == '\'' => return None,
+                c if c == quote => return Some(start + 1 + i + 1),
+                _ => {}
+            }
+        }
+        None
+    };
+    let mut stack = Vec::new();
+    let mut chars = code.char_indices().peekable();
+    let skip_to = |chars: &mut std::iter::Peekable<std::str::CharIndices>, end: usize| {
+        while chars.next_if(|&(j, _)| j < end).is_some() {}
+    };
+    while let Some((i, c)) = chars.next() {
+        let rest = &code[i..];
+        match c {
+            c if c.is_whitespace() => {}
+            '/' if rest.starts_with("//") => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '/' if rest.starts_with("/*") => {
+                // Block comments nest.
+                let mut depth = 0;
+                let mut j = i;
+                loop {
+                    if code[j..].starts_with("/*") {
+                        depth += 1;
+                        j += 2;
+                    } else if code[j..].starts_with("*/") {
+                        depth -= 1;
+                        j += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else if let Some(c) = code[j..].chars().next() {
+                        j += c.len_utf8();
+                    } else {
+                        return Some((i, "unterminated block comment".into()));
+                    }
+                }
+                skip_to(&mut chars, j);
+            }
+            '"' => match literal_end(i, '"') {
+                Some(end) => skip_to(&mut chars, end),
+                None => return Some((i, "unterminated string literal".into())),
+            },
+            '\'' => {
+                let mut next = rest[1..].chars();
+                match (next.next(), next.next()) {
+                    (Some('\\'), _) | (Some(_), Some('\'')) => match literal_end(i, '\'') {
+                        Some(end) => skip_to(&mut chars, end),
+                        None => return Some((i, "unterminated character literal".into())),
+                    },
+                    // A lifetime, like `'a`.
+                    (Some(c), _) if is_ident(c) => {}
+                    _ => return Some((i, "unterminated character literal".into())),
+                }
+            }
+            c if is_ident(c) => {
+                let len = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+                let (word, after) = rest.split_at(len);
+                let end = i + len;
+                let hashes = after.len() - after.trim_start_matches('#').len();
+                if matches!(word, "r" | "br" | "cr") && after[hashes..].starts_with('"') {
+                    let close = format!("\"{}", "#".repeat(hashes));
+                    let body = end + hashes + 1;
+                    match code[body..].find(&close) {
+                        Some(j) => skip_to(&mut chars, body + j + close.len()),
+                        None => return Some((i, "unterminated raw string".into())),
+                    }
+                } else if matches!(word, "b" | "c") && after.starts_with('"') {
+                    match literal_end(end, '"') {
+                        Some(end) => skip_to(&mut chars, end),
+                        None => return Some((i, "unterminated string literal".into())),
+                    }
+                } else if word == "b" && after.starts_with('\'') {
+                    match literal_end(end, '\'') {
+                        Some(end) => skip_to(&mut chars, end),
+                        None => return Some((i, "unterminated byte literal".into())),
+                    }
+                } else {
+                    skip_to(&mut chars, end);
+                }
+            }
+            '(' | '[' | '{' => stack.push((c, i)),
+            ')' | ']' | '}' => match stack.pop() {
+                None => return Some((i, format!("unexpected closing delimiter `{c}`"))),
+                Some((open, j)) if !matches!((open, c), ('(', ')') | ('[', ']') | ('{', '}')) => {
+                    return Some((
+                        i,
+                        format!("`{c}` does not match the `{open}` on line {}", line_of(j)),
+                    ));
+                }
+                Some(_) => {}
+            },
+            '~' | '!' | '@' | '#' | '$' | '%' | '^' | '&' | '*' | '-' | '+' | '=' | '|' | ';'
+            | ':' | ',' | '.' | '<' | '>' | '/' | '?' => {}
+            c => return Some((i, format!("unknown start of token {c:?}"))),
+        }
+    }
+    let &(open, j) = stack.first()?;
+    Some((j, format!("unclosed delimiter `{open}`")))
+}
+
+/// Show the line of the output that contains the byte `offset` (and the line before it),
+/// with its line number within the output, and a `^` under the character at `offset`.
+fn show_output_line(output: &str, offset: usize) -> String {
+    let start = output[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = output[offset..]
+        .find('\n')
+        .map_or(output.len(), |i| offset + i);
+    let line = output[..offset].matches('\n').count() + 1;
+    let width = line.to_string().len();
+    let mut shown = String::new();
+    if line > 1 {
+        let previous = output[..start - 1].rsplit('\n').next().unwrap();
+        writeln!(shown, "{:width$} | {previous}", line - 1).unwrap();
+    }
+    writeln!(shown, "{line} | {}", &output[start..end]).unwrap();
+    let column = output[start..offset].chars().count();
+    write!(
+        shown,
+        "{:width$} | {:column$}^ (line {line} of the output)",
+        "", ""
+    )
+    .unwrap();
+    shown
+}
+
+/// The start of the (escaped) output, to show in an error message.
+fn snippet(output: &str) -> String {
+    const MAX_LEN: usize = 200;
+    let output = output.trim();
+    match output.char_indices().nth(MAX_LEN) {
+        Some((end, _)) => format!("`{}`..", output[..end].escape_debug()),
+        None => format!("`{}`", output.escape_debug()),
+    }
+}
+
+/// Run the Python code, and return the generated Rust code.
+fn run_python(
+    lines: &Lines,
+    python: &str,
+    directives: &Directives,
+    output: Output,
+) -> Result<Generated, TokenStream> {
+    let python = python_cstring(python.to_owned(), lines)?;
+    let filename = directives.filename();
+
+    Python::with_gil(|py| {
+        let (result, warnings) = record_warnings(py, &filename, || {
+            let start = Instant::now();
+            let (code, value_code) = compile_python_with_value(py, &python, lines, directives, None)?;
+            if matches!(output, Output::Str | Output::Bytes) && value_code.is_none() {
+                return Err(missing_value_error(lines));
+            }
+            let compiled = start.elapsed();
+            let start = Instant::now();
+            let result = run_and_capture(py, code, value_code, directives, output);
+            report_profile(&[("compiled", compiled), ("ran", start.elapsed())]);
+            let (generated, value) = result
+                .map_err(|(err, stderr)| {
+                    // The message of `fail()` is shown by itself, without a traceback.
+                    let failed = err
+                        .value(py)
+                        .hasattr("_ct_python_fail")
+                        .unwrap_or(false)
+                        .then(|| err.value(py).str().map(|s| s.to_string()).ok())
+                        .flatten();
+                    let libpython_problem = LIBPYTHON_PROBLEM
+                        .get()
+                        .cloned()
+                        .flatten()
+                        .filter(|_| err.is_instance_of::<PyImportError>(py));
+                    let (spans, msg) = python_error_info(py, err, &filename, lines);
+                    let mut msg = failed.unwrap_or(msg);
+                    if let Some(problem) = libpython_problem {
+                        write!(msg, "\n\nnote: this might be because the symbols of libpython are not available to extension modules: {problem}").unwrap();
+                    }
+                    if !stderr.is_empty() {
+                        write!(msg, "\n\nstderr:\n{}", stderr.trim_end()).unwrap();
+                    }
+                    compile_error(spans, &msg)
+                })?;
+            let literal = match (output, value.map(|v| v.into_bound(py))) {
+                (Output::Str, Some(v)) => v.str().map(|s| Literal::string(&s.to_string_lossy())),
+                (Output::Bytes, Some(v)) => {
+                    v.extract::<Vec<u8>>().map(|b| Literal::byte_string(&b))
+                }
+                _ => return Ok(generated),
+            };
+            eprint!("{}", generated.code);
+            let literal =
+                literal.map_err(|err| python_error_to_compile_error(py, err, &filename, lines))?;
+            Ok(Generated {
+                code: literal.to_string(),
+                ..generated
+            })
+        })
+        .map_err(|err| python_error_to_compile_error(py, err, &filename, lines))?;
+        // Proc macros can't emit warnings on stable Rust, and unlike for `python!{}`,
+        // we can't use the deprecated item trick, since we don't know where the output goes.
+        for warning in warnings {
+            eprintln!("warning: python: {}", warning.message);
+            if let Some(line) = warning.line {
+                eprintln!("  --> {}:{line}", Span::call_site().file());
+            }
+        }
+        result
+    })
+}
+
+/// Run the code, capturing stdout and stderr.
+///
+/// If `value_code` is given, it is evaluated afterwards, and its value is returned.
+///
+/// On success, the captured stderr is written to the original stderr,
+/// and the captured stdout is returned, together with the files that were read.
+/// On failure, the error is returned together with the captured stderr.
+fn run_and_capture(
+    py: Python,
+    code: PyObject,
+    value_code: Option<PyObject>,
+    directives: &Directives,
+    output: Output,
+) -> Result<(Generated, Option<PyObject>), (PyErr, String)> {
+    let mut stderr = String::new();
+    let result = run_and_capture_inner(py, code, value_code, directives, output, &mut stderr);
+    result.map_err(|err| (err, stderr))
+}
+
+/// Defines `make_helpers(dir, namespace, files, output)`, which makes the `include(path)`,
+/// `open(..)`, `fail(message)`, and `emit(..)` functions that are available to the Python code.
+/// `include` and `open` record the paths of the files they read in `files`.
+/// The exception raised by `fail` has a `_ct_python_fail` attribute.
+/// `emit` writes to `output`, and sets its own `used` attribute.
+///
+/// Also defines `LimitedOutput(limit)`, a `StringIO` that fails the compilation (like `fail`)
+/// once more than `limit` bytes (UTF-8 encoded) are written to it,
+/// `export_module(name, namespace, skip)`, which puts the namespace (except the names in the list `skip`)
+/// in `sys.modules` as a module called `name`,
+/// and `rust_float(x, type)`, `rust_complex(z, type)` and `rust_type_of(value)`,
+/// which are available to the Python code directly.
+const MAKE_HELPERS: &CStr = cr#"
+import io as _io
+import math as _math
+def rust_float(x, type="f64"):
+    "Format a number as a Rust float literal (or constant, for infinity and NaN) of the given type."
+    if type not in ("f32", "f64"):
+        raise ValueError("type must be 'f32' or 'f64', not %r" % (type,))
+    x = float(x)
+    if _math.isnan(x):
+        return type + "::NAN"
+    if _math.isinf(x):
+        return type + ("::INFINITY" if x > 0 else "::NEG_INFINITY")
+    return repr(x) + type
+def rust_complex(z, type="f64"):
+    "Format a number as a Rust num_complex::Complex expression with parts of the given float type."
+    z = complex(z)
+    name = {"f32": "Complex32", "f64": "Complex64"}.get(type)
+    if name is None:
+        raise ValueError("type must be 'f32' or 'f64', not %r" % (type,))
+    return "::num_complex::%s::new(%s, %s)" % (name, rust_float(z.real, type), rust_float(z.imag, type))
+def rust_type_of(value):
+    "A best-effort Rust type for the given Python value."
+    def element_type(kind, values):
+        types = set(map(rust_type_of, values))
+        if not types:
+            raise ValueError("can't determine the element type of an empty %s" % kind)
+        if len(types) > 1:
+            raise TypeError("%s has elements of different types: %s" % (kind, ", ".join(sorted(types))))
+        return types.pop()
+    if value is None:
+        return "()"
+    if isinstance(value, bool):
+        return "bool"
+    if isinstance(value, int):
+        for name, bits in (("i64", 63), ("i128", 127)):
+            if -(1 << bits) <= value < (1 << bits):
+                return name
+        if 0 <= value < (1 << 128):
+            return "u128"
+        raise ValueError("integer does not fit in any Rust integer type: %d" % value)
+    if isinstance(value, float):
+        return "f64"
+    if isinstance(value, complex):
+        return "::num_complex::Complex64"
+    if isinstance(value, str):
+        return "&str"
+    if isinstance(value, (bytes, bytearray)):
+        return "&[u8]"
+    if isinstance(value, tuple):
+        types = ", ".join(map(rust_type_of, value))
+        return "(%s,)" % types if len(value) == 1 else "(%s)" % types
+    if isinstance(value, list):
+        return "Vec<%s>" % element_type("list", value)
+    if isinstance(value, (set, frozenset)):
+        return "::std::collections::HashSet<%s>" % element_type("set", value)
+    if isinstance(value, dict):
+        key = element_type("dict", value.keys())
+        return "::std::collections::HashMap<%s, %s>" % (key, element_type("dict", value.values()))
+    raise TypeError("no Rust type known for Python type %r" % type(value).__name__)
+def export_module(name, namespace, skip):
+    import importlib.util, sys, types
+    existing = sys.modules.get(name)
+    if existing is None:
+        taken = importlib.util.find_spec(name) is not None
+    else:
+        taken = not getattr(existing, "_ct_python_export", False)
+    if taken:
+        raise ImportError("can't export module %r: a module with that name already exists" % name)
+    module = types.ModuleType(name)
+    for key, value in namespace.items():
+        if key not in skip and not (key.startswith("__") and key.endswith("__")):
+            setattr(module, key, value)
+    module._ct_python_export = True
+    sys.modules[name] = module
+class LimitedOutput(_io.StringIO):
+    def __init__(self, limit):
+        super().__init__()
+        self.limit = limit
+        self.size = 0
+    def write(self, s):
+        self.size += len(s.encode("utf-8", "surrogatepass"))
+        if self.size > self.limit:
+            e = Exception(
+                "the output is larger than the maximum of %d bytes "
+                "(set INLINE_PYTHON_MAX_OUTPUT_BYTES to change the limit)" % self.limit
+            )
+            e._ct_python_fail = True
+            raise e
+        return super().write(s)
+def make_helpers(dir, namespace, files, output):
+    import builtins, os
+    def include(path):
+        "Run a Python file in this namespace. Relative paths are relative to the Rust file."
+        path = os.path.join(dir, path)
+        files.append(os.path.abspath(path))
+        with builtins.open(path, encoding="utf-8") as f:
+            code = compile(f.read(), path, "exec")
+        exec(code, namespace)
+    def open(file, mode="r", *args, **kwargs):
+        if isinstance(file, (str, bytes, os.PathLike)) and not any(c in mode for c in "wax"):
+            files.append(os.path.abspath(os.fsdecode(file)))
+        return builtins.open(file, mode, *args, **kwargs)
+    open.__doc__ = builtins.open.__doc__
+    class CompileError(Exception):
+        _ct_python_fail = True
+    def fail(message):
+        "Fail the compilation with the given error message."
+        raise CompileError(message)
+    def emit(*args, sep=" ", end="\n"):
+        "Add Rust code to the output, like print(). Once this is used, print() is no longer part of the output."
+        emit.used = True
+        builtins.print(*args, sep=sep, end=end, file=output)
+    emit.used = False
+    return include, open, fail, emit
+"#;
+
+fn run_and_capture_inner(
+    py: Python,
+    code: PyObject,
+    value_code: Option<PyObject>,
+    directives: &Directives,
+    output: Output,
+    stderr_output: &mut String,
+) -> PyResult<(Generated, Option<PyObject>)> {
+    prepare_interpreter(py)?;
 
     let globals = py.import("__main__")?.dict().copy()?;
 
+    let dir = Span::call_site()
+        .local_file()
+        .and_then(|file| Some(file.parent()?.to_path_buf()))
+        .unwrap_or_default();
+    let string_io = py.import("io")?.getattr("StringIO")?;
+    let make_helpers = PyDict::new(py);
+    py.run(MAKE_HELPERS, Some(&make_helpers), None)?;
+    let limited_output = make_helpers.get_item("LimitedOutput")?.unwrap();
+    let max_output = max_output_bytes();
+    // With `//! output: file`, both `print()` and `emit()` write to the file.
+    let output_file = (output == Output::File).then(output_file_path);
+    let emitted = match &output_file {
+        Some(path) => py.import("builtins")?.getattr("open")?.call(
+            (path, "w"),
+            Some(&[("encoding", "utf-8")].into_py_dict(py)?),
+        )?,
+        None => limited_output.call1((max_output,))?,
+    };
+
+    let files = PyList::empty(py);
+    let (include, open, fail, emit) = make_helpers
+        .get_item("make_helpers")?
+        .unwrap()
+        .call1((dir, &globals, &files, &emitted))?
+        .extract::<(PyObject, PyObject, PyObject, Bound<PyAny>)>()?;
+    globals.set_item("include", include)?;
+    globals.set_item("open", open)?;
+    globals.set_item("fail", fail)?;
+    globals.set_item("emit", &emit)?;
+    for helper in ["rust_float", "rust_complex", "rust_type_of"] {
+        globals.set_item(helper, make_helpers.get_item(helper)?)?;
+    }
+
+    let site = Span::call_site();
+    let call_site = PyDict::new(py);
+    call_site.set_item("file", site.file())?;
+    call_site.set_item("line", site.line())?;
+    call_site.set_item("column", site.column())?;
+    call_site.set_item("crate", std::env::var("CARGO_CRATE_NAME").ok())?;
+    globals.set_item("call_site", call_site)?;
+
+    let environ = py.import("os")?.getattr("environ")?;
+    for (key, value) in std::env::vars() {
+        if key.starts_with("CARGO_") || key == "OUT_DIR" {
+            environ.set_item(key, value)?;
+        }
+    }
+
     let sys = py.import("sys")?;
-    let stdout = py.import("io")?.getattr("StringIO")?.call0()?;
-    let original_stdout = sys.dict().get_item("stdout")?;
+    // Put back the original `sys.stdout`, `sys.stderr`, and `sys.path` when this function returns,
+    // also if it returns early with an error, since the next invocation uses the same interpreter.
+    let original_stderr = sys.dict().get_item("stderr")?;
+    let restore = RestoreSys {
+        stdout: sys.dict().get_item("stdout")?,
+        stderr: original_stderr.clone(),
+        path: sys.getattr("path")?.call_method0("copy")?,
+        sys: sys.clone(),
+    };
+    if let Some(manifest_dir) = std::env::var_os("CARGO_MANIFEST_DIR") {
+        let path = sys.getattr("path")?;
+        match directives.sys_path.as_deref() {
+            Some("false") => {}
+            dir => {
+                let dir = Path::new(&manifest_dir).join(dir.unwrap_or("python"));
+                path.call_method1("insert", (0, dir))?;
+                path.call_method1("insert", (0, &manifest_dir))?;
+            }
+        }
+    }
+
+    let stdout = match &output_file {
+        Some(_) => emitted.clone(),
+        None => limited_output.call1((max_output,))?,
+    };
+    let stderr = string_io.call0()?;
     sys.dict().set_item("stdout", &stdout)?;
+    sys.dict().set_item("stderr", &stderr)?;
 
-    let result = unsafe {
+    // Everything that's already defined, to leave out of an exported module.
+    let predefined = globals.keys();
+    let eval = |code: &PyObject| unsafe {
         let ptr = pyo3::ffi::PyEval_EvalCode(code.as_ptr(), globals.as_ptr(), null_mut());
         PyObject::from_owned_ptr_or_err(py, ptr)
     };
+    let timeout = directives.timeout.or_else(|| {
+        let secs = std::env::var("INLINE_PYTHON_TIMEOUT_SECS").ok()?;
+        secs.trim().parse().ok()
+    });
+    let watchdog = timeout.map(|secs| Watchdog::start(py, secs)).transpose()?;
+    let result = eval(&code).and_then(|_| value_code.as_ref().map(eval).transpose());
+    if let Some(watchdog) = watchdog
+        && watchdog.stop(py)
+        && let Err(err) = &result
+        && err.is_instance_of::<PyTimeoutError>(py)
+    {
+        let secs = timeout.unwrap();
+        let s = if secs == 1 { "" } else { "s" };
+        let msg = format!("the code did not finish within {secs} second{s}");
+        // If this fails, the original `TimeoutError` is still reported.
+        let _ = err.value(py).setattr("args", (msg,));
+    }
+
+    drop(restore);
+
+    *stderr_output = stderr.call_method0("getvalue")?.extract()?;
+
+    let value = result?;
+
+    if let Some(name) = &directives.export {
+        let export_module = make_helpers.get_item("export_module")?.unwrap();
+        export_module.call1((name, &globals, predefined))?;
+    }
+
+    // Once `emit()` is used, `print()` output is shown like stderr, instead of used as output.
+    let emit_used = emit.getattr("used")?.is_truthy()?;
+    let printed = if emit_used && output_file.is_none() {
+        stdout.call_method0("getvalue")?.extract()?
+    } else {
+        String::new()
+    };
+
+    if let Some(original_stderr) = original_stderr {
+        for text in [&*stderr_output, &printed] {
+            if !text.is_empty() {
+                original_stderr.call_method1("write", (text,))?;
+            }
+        }
+    }
+
+    let files = files.extract()?;
+
+    let rust_code = match output_file {
+        Some(path) => {
+            emitted.call_method0("close")?;
+            let path = Literal::string(&path.to_string_lossy());
+            format!("::core::include!{{{path}}}")
+        }
+        None if emit_used => emitted.call_method0("getvalue")?.extract()?,
+        None => stdout.call_method0("getvalue")?.extract()?,
+    };
+
+    Ok((
+        Generated {
+            code: rust_code,
+            files,
+        },
+        value,
+    ))
+}
+
+/// The maximum size of the captured output, from `INLINE_PYTHON_MAX_OUTPUT_BYTES`, or 16 MiB by default.
+fn max_output_bytes() -> u64 {
+    std::env::var("INLINE_PYTHON_MAX_OUTPUT_BYTES")
+        .ok()
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(16 << 20)
+}
+
+/// The file in `OUT_DIR` that the output of this invocation is written to, with `//! output: file`.
+fn output_file_path() -> PathBuf {
+    let site = Span::call_site();
+    let file = site
+        .file()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let name = format!("ct_python_{file}_{}_{}.rs", site.line(), site.column());
+    Path::new(&std::env::var_os("OUT_DIR").unwrap()).join(name)
+}
+
+/// The original values of `sys.stdout`, `sys.stderr`, and `sys.path`, which are put back when dropped.
+struct RestoreSys<'py> {
+    sys: Bound<'py, PyModule>,
+    stdout: Option<Bound<'py, PyAny>>,
+    stderr: Option<Bound<'py, PyAny>>,
+    path: Bound<'py, PyAny>,
+}
+
+impl Drop for RestoreSys<'_> {
+    fn drop(&mut self) {
+        // There's nothing better to do with an error here than to ignore it.
+        let _ = self.sys.dict().set_item("stdout", &self.stdout);
+        let _ = self.sys.dict().set_item("stderr", &self.stderr);
+        let _ = self.sys.setattr("path", &self.path);
+    }
+}
+
+/// Raises a `TimeoutError` in the thread running the Python code after a number of seconds.
+struct Watchdog {
+    /// Set (with the GIL held) once the code is done, to disarm the watchdog.
+    done: Arc<AtomicBool>,
+    /// Set by the watchdog when it raised the exception.
+    fired: Arc<AtomicBool>,
+    thread_id: c_long,
+    /// Wakes up the watchdog thread when it is dropped.
+    _stop: mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    fn start(py: Python, secs: u64) -> PyResult<Self> {
+        let thread_id = py.import("threading")?.getattr("get_ident")?.call0()?;
+        let thread_id = thread_id.extract::<u64>()? as c_long;
+        let done = Arc::new(AtomicBool::new(false));
+        let fired = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (done2, fired2) = (done.clone(), fired.clone());
+        std::thread::spawn(move || {
+            if stopped.recv_timeout(Duration::from_secs(secs)) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            // The Python code regularly releases the GIL, which allows us to get it here.
+            Python::with_gil(|_| {
+                if !done2.load(Ordering::Relaxed) {
+                    fired2.store(true, Ordering::Relaxed);
+                    unsafe {
+                        pyo3::ffi::PyThreadState_SetAsyncExc(
+                            thread_id,
+                            pyo3::ffi::PyExc_TimeoutError,
+                        );
+                    }
+                }
+            });
+        });
+        Ok(Self {
+            done,
+            fired,
+            thread_id,
+            _stop: stop,
+        })
+    }
 
-    sys.dict().set_item("stdout", original_stdout)?;
+    /// Disarm the watchdog, and return whether it raised the exception.
+    ///
+    /// This does not wait for the watchdog thread, which might be waiting for the GIL.
+    fn stop(self, _py: Python) -> bool {
+        self.done.store(true, Ordering::Relaxed);
+        let fired = self.fired.load(Ordering::Relaxed);
+        if fired {
+            // Clear the exception, in case the code finished before it was raised.
+            unsafe { pyo3::ffi::PyThreadState_SetAsyncExc(self.thread_id, null_mut()) };
+        }
+        fired
+    }
+}
 
-    result?;
+/// The result of [`add_virtualenv_site_packages`], which only runs once per process,
+/// since `sys.path` is kept between invocations.
+static VIRTUALENV_ADDED: OnceLock<PyResult<()>> = OnceLock::new();
 
-    stdout.call_method0("getvalue")?.extract()
+/// Make the packages installed in the active virtual environment (`VIRTUAL_ENV`) importable.
+fn add_virtualenv_site_packages(py: Python) -> PyResult<()> {
+    let Some(venv) = std::env::var_os("VIRTUAL_ENV") else {
+        return Ok(());
+    };
+    let site_packages = if cfg!(windows) {
+        Path::new(&venv).join("Lib").join("site-packages")
+    } else {
+        let version = py.version_info();
+        Path::new(&venv)
+            .join("lib")
+            .join(format!("python{}.{}", version.major, version.minor))
+            .join("site-packages")
+    };
+    // This does nothing if the directory is already in `sys.path`.
+    py.import("site")?
+        .call_method1("addsitedir", (site_packages,))?;
+    Ok(())
 }
 
+/// Make the symbols of libpython available to extension modules, and add the packages of the virtualenv.
+fn prepare_interpreter(py: Python) -> PyResult<()> {
+    // Both of these only do any work the first time.
+    #[cfg(any(unix, windows))]
+    LIBPYTHON_PROBLEM.get_or_init(|| {
+        #[cfg(test)]
+        tests::LIBPYTHON_CHECKS.fetch_add(1, Ordering::Relaxed);
+        ensure_libpython_symbols_loaded(py).err()
+    });
+    VIRTUALENV_ADDED
+        .get_or_init(|| add_virtualenv_site_packages(py))
+        .as_ref()
+        .map_err(|err| err.clone_ref(py))?;
+    Ok(())
+}
+
+/// If the symbols of libpython could not be made globally available, the reason why.
+///
+/// This is only checked once, and only used to explain import errors.
+static LIBPYTHON_PROBLEM: OnceLock<Option<String>> = OnceLock::new();
+
 #[cfg(unix)]
-fn ensure_libpython_symbols_loaded(py: Python) -> PyResult<()> {
+fn ensure_libpython_symbols_loaded(py: Python) -> Result<(), String> {
     // On Unix, Rustc loads proc-macro crates with RTLD_LOCAL, which (at least
     // on Linux) means all their dependencies (in our case: libpython) don't
     // get their symbols made available globally either. This means that
@@ -99,16 +1516,221 @@ fn ensure_libpython_symbols_loaded(py: Python) -> PyResult<()> {
     //
     // This function tries to (re)load the right version of libpython, but this
     // time with RTLD_GLOBAL enabled.
-    let sysconfig = py.import("sysconfig")?;
-    let libdir: String = sysconfig
-        .getattr("get_config_var")?
-        .call1(("LIBDIR",))?
-        .extract()?;
-    let so_name: String = sysconfig
-        .getattr("get_config_var")?
-        .call1(("INSTSONAME",))?
-        .extract()?;
-    let path = CString::new(format!("{libdir}/{so_name}")).unwrap();
-    unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) };
+    if !unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"Py_IsInitialized".as_ptr()) }.is_null() {
+        // The symbols are already globally available.
+        return Ok(());
+    }
+    let config_var = |name: &str| -> Result<Option<Bound<PyAny>>, String> {
+        let value = py
+            .import("sysconfig")
+            .and_then(|sysconfig| sysconfig.getattr("get_config_var")?.call1((name,)))
+            .map_err(|e| format!("unable to get `{name}` from `sysconfig`: {e}"))?;
+        Ok((!value.is_none()).then_some(value))
+    };
+    if !config_var("Py_ENABLE_SHARED")?.is_some_and(|v| v.is_truthy().unwrap_or(false)) {
+        return Err("Python was built without a shared libpython \
+            (`Py_ENABLE_SHARED` is not set), \
+            so extension modules can't find the Python symbols they need"
+            .into());
+    }
+    let (Some(libdir), Some(so_name)) = (config_var("LIBDIR")?, config_var("INSTSONAME")?) else {
+        return Err(
+            "unable to find the shared libpython (`LIBDIR` or `INSTSONAME` is not set)".into(),
+        );
+    };
+    let path = format!("{libdir}/{so_name}");
+    let c_path = CString::new(path.as_str()).unwrap();
+    if unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) }.is_null() {
+        let error = unsafe { libc::dlerror() };
+        let error = if error.is_null() {
+            "unknown error".into()
+        } else {
+            unsafe { CStr::from_ptr(error) }.to_string_lossy()
+        };
+        return Err(format!("unable to load `{path}`: {error}"));
+    }
     Ok(())
 }
+
+#[cfg(windows)]
+fn ensure_libpython_symbols_loaded(py: Python) -> Result<(), String> {
+    // On Windows, extension modules link to `pythonXY.dll` by name, and the
+    // loader reuses the already loaded DLL with that name, so there is nothing
+    // to do, as long as our libpython has that name. If it doesn't (e.g. when
+    // Python is linked statically), extension modules would load or fail to
+    // find a different copy of Python.
+    unsafe extern "system" {
+        fn GetModuleHandleW(name: *const u16) -> *mut std::ffi::c_void;
+    }
+    let version = py.version_info();
+    let name = format!("python{}{}.dll", version.major, version.minor);
+    let wide_name = name.encode_utf16().chain([0]).collect::<Vec<u16>>();
+    if unsafe { GetModuleHandleW(wide_name.as_ptr()) }.is_null() {
+        return Err(format!(
+            "`{name}` is not loaded, so extension modules can't use the already running Python"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// The number of times [`ensure_libpython_symbols_loaded`] was called.
+    pub(super) static LIBPYTHON_CHECKS: AtomicUsize = AtomicUsize::new(0);
+
+    fn error(offset: usize, msg: &str) -> Option<(usize, String)> {
+        Some((offset, msg.to_string()))
+    }
+
+    #[test]
+    fn lex_error_in_literals() {
+        assert_eq!(find_lex_error(r#"let s = "a\"b"; x"#), None);
+        assert_eq!(
+            find_lex_error(r#"let s = "a\"b"#),
+            error(8, "unterminated string literal")
+        );
+        assert_eq!(
+            find_lex_error(r#"let s = b"a"#),
+            error(8, "unterminated string literal")
+        );
+        assert_eq!(
+            find_lex_error(r##"r#"a"b"# r#"a""##),
+            error(9, "unterminated raw string")
+        );
+        assert_eq!(find_lex_error(r"let c = '\''; let d = 'x';"), None);
+        assert_eq!(
+            find_lex_error(r"let c = '\x;"),
+            error(8, "unterminated character literal")
+        );
+        assert_eq!(
+            find_lex_error("let c = '  ;"),
+            error(8, "unterminated character literal")
+        );
+        assert_eq!(
+            find_lex_error("let c = b'a;"),
+            error(8, "unterminated byte literal")
+        );
+        assert_eq!(find_lex_error("fn f<'a>(x: &'a str) {}"), None);
+        assert_eq!(find_lex_error("// \"\nx /* /* \" */ */"), None);
+        assert_eq!(
+            find_lex_error("x /* /* */"),
+            error(2, "unterminated block comment")
+        );
+    }
+
+    #[test]
+    fn lex_error_in_delimiters() {
+        assert_eq!(find_lex_error("fn f() { g([1, (2)]) }"), None);
+        assert_eq!(
+            find_lex_error("fn f() { g(1] }"),
+            error(12, "`]` does not match the `(` on line 1")
+        );
+        assert_eq!(
+            find_lex_error("{\n  (\n]"),
+            error(6, "`]` does not match the `(` on line 2")
+        );
+        assert_eq!(
+            find_lex_error("{ ( [ ] )"),
+            error(0, "unclosed delimiter `{`")
+        );
+        assert_eq!(
+            find_lex_error("x)"),
+            error(1, "unexpected closing delimiter `)`")
+        );
+        assert_eq!(
+            find_lex_error("\"(\" ')' ("),
+            error(8, "unclosed delimiter `(`")
+        );
+    }
+
+    #[test]
+    fn lex_error_after_multi_byte_characters() {
+        assert_eq!(
+            find_lex_error(r#"let s = "é"; "x"#),
+            error(14, "unterminated string literal")
+        );
+        assert_eq!(
+            find_lex_error("let é = 'ü'; €"),
+            error(15, "unknown start of token '€'")
+        );
+    }
+
+    #[test]
+    fn split_into_items() {
+        assert_eq!(
+            split_items("struct A; fn f() { g(); } const X: u8 = b'}';"),
+            ["struct A;", "fn f() { g(); }", "const X: u8 = b'}';"],
+        );
+        assert_eq!(
+            split_items("const S: &str = \"};\"; const É: &str = r#\"é;\"#; struct B;"),
+            [
+                "const S: &str = \"};\";",
+                "const É: &str = r#\"é;\"#;",
+                "struct B;"
+            ],
+        );
+        assert_eq!(
+            split_items("fn f<'a>(x: &'a str) {} // };\nstruct B; x + 1"),
+            ["fn f<'a>(x: &'a str) {}", "// };\nstruct B;", "x + 1"],
+        );
+    }
+
+    #[test]
+    fn split_unbalanced_items() {
+        assert_eq!(
+            split_items("fn f() { (; } struct B;"),
+            ["fn f() { (; } struct B;"]
+        );
+        assert_eq!(
+            split_items("fn f() { g()) } struct B;"),
+            ["fn f() { g()) }", "struct B;"]
+        );
+    }
+
+    #[test]
+    fn split_items_spanning_lines() {
+        assert_eq!(
+            split_items("fn f() -> u8 {\n    1\n}\n\nstruct A {\n    x: u8,\n}\nstruct B;\n"),
+            [
+                "fn f() -> u8 {\n    1\n}",
+                "struct A {\n    x: u8,\n}",
+                "struct B;"
+            ],
+        );
+    }
+
+    #[test]
+    fn show_line_of_output() {
+        assert_eq!(
+            show_output_line("abc", 0),
+            "1 | abc\n  | ^ (line 1 of the output)"
+        );
+        assert_eq!(
+            show_output_line("abc\ndef\nghi", 5),
+            "1 | abc\n2 | def\n  |  ^ (line 2 of the output)",
+        );
+        assert_eq!(
+            show_output_line("é = 1\nçà x\n", 12),
+            "1 | é = 1\n2 | çà x\n  |    ^ (line 2 of the output)",
+        );
+        let output = (1..=10).map(|i| format!("l{i}\n")).collect::<String>();
+        assert_eq!(
+            show_output_line(&output, output.find("l10").unwrap() + 1),
+            " 9 | l9\n10 | l10\n   |  ^ (line 10 of the output)",
+        );
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn libpython_is_checked_once() {
+        Python::with_gil(|py| {
+            prepare_interpreter(py).unwrap();
+            prepare_interpreter(py).unwrap();
+            py.import("math").unwrap();
+        });
+        assert_eq!(LIBPYTHON_CHECKS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,51 @@
+//! Compares ways of passing a large string to Python.
+//!
+//! Run with `cargo run --release --example large-str`.
+
+use inline_python::{AsMemoryView, Context, python};
+use std::time::Instant;
+
+fn main() {
+    let line = "Voix ambiguë d'un cœur qui, au zéphyr, préfère les jattes de kiwis.\n";
+    let text = line.repeat(100_000); // 7.3 MB.
+    let lines = text.lines().count();
+
+    let c = Context::new();
+    c.run(python! {
+        import re
+    });
+
+    // A `str` is decoded from UTF-8 and copied into a new Python object.
+    let start = Instant::now();
+    c.run(python! {
+        text = 'ref text
+    });
+    println!("str:        {:?}", start.elapsed());
+
+    // A byte slice becomes `bytes`: still a copy, but without decoding.
+    let start = Instant::now();
+    c.run(python! {
+        data = '_(text.as_bytes())
+    });
+    println!("bytes:      {:?}", start.elapsed());
+
+    // A memoryview points at the Rust string directly, so nothing is copied.
+    // It must not be used after the block, so it's not kept in a global variable.
+    let view = unsafe { AsMemoryView::new(text.as_bytes()) };
+    let start = Instant::now();
+    c.run(python! {
+        view_size = 'view.nbytes
+    });
+    println!("memoryview: {:?}", start.elapsed());
+
+    // Many functions that take `bytes` also take a memoryview.
+    let view = unsafe { AsMemoryView::new(text.as_bytes()) };
+    c.run(python! {
+        view_lines = len(re.findall(b"\n", 'view))
+    });
+
+    c.run(python! {
+        assert text.count("\n") == data.count(b"\n") == view_lines == 'lines
+        assert len(data) == view_size
+    });
+}
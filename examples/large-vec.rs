@@ -0,0 +1,71 @@
+//! Compares ways of passing a large `Vec` to Python.
+//!
+//! Run with `cargo run --release --example large-vec`.
+
+use inline_python::{AsMemoryView, Context, python};
+use pyo3::{
+    prelude::*,
+    types::{PyList, PyTuple},
+};
+use std::time::Instant;
+
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+impl<'py> IntoPyObject<'py> for &Point {
+    type Target = PyTuple;
+    type Output = Bound<'py, PyTuple>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Self::Output> {
+        (self.x, self.y).into_pyobject(py)
+    }
+}
+
+fn main() {
+    let points: Vec<Point> = (0..1_000_000)
+        .map(|i| Point {
+            x: i as f64,
+            y: -i as f64,
+        })
+        .collect();
+
+    let c = Context::new();
+
+    // A captured `Vec` becomes a list that is allocated once, at its final size.
+    let start = Instant::now();
+    c.run(python! {
+        points = 'ref points
+    });
+    println!("capture:        {:?}", start.elapsed());
+
+    // The naive loop grows the list one `append` at a time.
+    // This takes about as long, since nearly all the time goes to creating
+    // the Python objects for the elements, not to growing the list.
+    let start = Instant::now();
+    Python::with_gil(|py| {
+        let list = PyList::empty(py);
+        for point in &points {
+            list.append(point).unwrap();
+        }
+        c.globals().bind(py).set_item("points", list).unwrap();
+    });
+    println!("append in loop: {:?}", start.elapsed());
+
+    // Numeric data can be passed without converting every element up front.
+    let xs: Vec<f64> = points.iter().map(|p| p.x).collect();
+    let start = Instant::now();
+    let view = unsafe { AsMemoryView::new(&xs) };
+    c.run(python! {
+        total = sum('view)
+    });
+    println!("memoryview:     {:?}", start.elapsed());
+
+    c.run(python! {
+        assert total == sum(x for x, _ in points)
+        assert len(points) == 1_000_000
+        assert points[-1] == (999_999.0, -999_999.0)
+    });
+}
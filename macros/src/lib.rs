@@ -2,12 +2,9 @@
 
 extern crate proc_macro;
 
-use proc_macro::{Literal, Span, TokenStream, TokenTree};
-use pyo3::{Py, Python};
-use std::{
-    collections::BTreeMap,
-    ffi::{CStr, CString},
-};
+use proc_macro::{Delimiter, Group, Ident, Literal, Spacing, Span, TokenStream, TokenTree};
+use pyo3::{Py, PyResult, Python, prelude::*, types::PyTuple};
+use std::{collections::BTreeMap, ffi::CStr, str::FromStr, time::Instant};
 
 mod shared;
 use shared::*;
@@ -15,38 +12,461 @@ use shared::*;
 #[doc(hidden)]
 #[proc_macro]
 pub fn python(input: TokenStream) -> TokenStream {
-    python_impl(input).unwrap_or_else(|e| e)
+    python_impl(input, Kind::Block, &[]).unwrap_or_else(|e| e)
 }
 
+#[doc(hidden)]
+#[proc_macro]
+pub fn python_eval(input: TokenStream) -> TokenStream {
+    python_impl(input, Kind::Eval, &[]).unwrap_or_else(|e| e)
+}
+
+#[doc(hidden)]
+#[proc_macro]
+pub fn python_fn(input: TokenStream) -> TokenStream {
+    python_impl(input, Kind::Fn, &[]).unwrap_or_else(|e| e)
+}
+
+#[doc(hidden)]
+#[proc_macro_attribute]
+pub fn python_body(attr: TokenStream, item: TokenStream) -> TokenStream {
+    python_body_impl(attr, item).unwrap_or_else(|e| e)
+}
+
+/// Which macro is used.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// `python!{}`
+    Block,
+    /// `python_eval!{}`, which requires the code to end in an expression.
+    Eval,
+    /// `python_fn!{}`, which requires the code to be a single `def` statement.
+    Fn,
+    /// `#[python_body]`, where the code is the body of a function with the given parameters.
+    Body,
+}
+
+/// Expand a `python!{}`, `python_eval!{}` or `python_fn!{}` block, or the body of a `#[python_body]` function.
+///
+/// The `params` are the parameters of the function, for [`Kind::Body`].
 #[rustfmt::skip]
-fn python_impl(input: TokenStream) -> Result<TokenStream, TokenStream> {
+fn python_impl(input: TokenStream, kind: Kind, params: &[Ident]) -> Result<TokenStream, TokenStream> {
+    let (directives, input) = parse_directives(input)?;
+    if !directives.cache {
+        return Err(compile_error(None, "the `cache` directive is only supported by `ct_python`"));
+    }
+    if directives.sys_path.is_some() {
+        return Err(compile_error(None, "the `sys_path` directive is only supported by `ct_python`"));
+    }
+    if directives.timeout.is_some() {
+        return Err(compile_error(None, "the `timeout` directive is only supported by `ct_python`"));
+    }
+    if directives.output_file {
+        return Err(compile_error(None, "the `output` directive is only supported by `ct_python`"));
+    }
+    if directives.export.is_some() {
+        return Err(compile_error(None, "the `export` directive is only supported by `ct_python`"));
+    }
+    if kind == Kind::Body && directives.mode != Mode::Exec {
+        return Err(compile_error(None, "the `mode` directive is not supported by `#[python_body]`"));
+    }
+    let (context, input) = match kind {
+        Kind::Body => (None, input),
+        _ => parse_context(input)?,
+    };
+    if kind == Kind::Fn && let Some(context) = &context {
+        let span = context.span();
+        return Err(compile_error(Some((span, span)), "`in context;` is not supported by `python_fn!{}`"));
+    }
     let mut variables = BTreeMap::new();
-    let python = CString::new(python_from_macro(input.clone(), Some(&mut variables))?).unwrap();
-    let filename = CString::new(Span::call_site().file()).unwrap();
-    let bytecode = compile_to_bytecode(&python, &filename, input)?;
-    Ok(TokenStream::from_iter([
+    let (python, lines) = python_from_macro(input, Some(&mut variables))?;
+    let param_names: Vec<String> = params.iter().map(param_name).collect();
+    for (param, name) in params.iter().zip(&param_names) {
+        variables.entry(name.clone()).or_insert_with(|| Capture::Value(TokenTree::Ident(param.clone())));
+    }
+    dump_python(&python);
+    let python = python_cstring(python, &lines)?;
+    let function_name = match kind {
+        Kind::Fn => Some(function_name(&python, &lines, &directives)?),
+        _ => None,
+    };
+    let function = (kind == Kind::Body).then_some(&param_names[..]);
+    let (bytecode, warnings) = compile_to_bytecode(&python, &lines, kind == Kind::Eval, &directives, function)?;
+    let block = TokenStream::from_iter([
         punct(':'), punct(':'), ident("inline_python"),
         punct(':'), punct(':'), ident("_python_block"),
         punct('!'),
         braces(
-            [TokenTree::Literal(bytecode)].into_iter()
-            .chain(variables.into_iter().map(|(_, value)| TokenTree::Ident(value)))
+            match (kind, function_name) {
+                (Kind::Eval | Kind::Body, _) => vec![ident("eval")],
+                (Kind::Fn, Some(name)) => vec![ident("fn"), string(&name)],
+                _ => vec![],
+            }.into_iter()
+            .chain(context.map(|context| [ident("in"), TokenTree::Group(context)]).into_iter().flatten())
+            .chain([TokenTree::Literal(bytecode), line_columns(&lines)])
+            // The `'mut` variables come first, as `&mut _RUST_var = var;`.
+            .chain(variables.iter().flat_map(|(name, capture)| match capture {
+                Capture::Mut(var) => vec![
+                    punct('&'), ident("mut"),
+                    ident(&format!("_RUST_{name}")), punct('='), TokenTree::Ident(var.clone()), punct(';'),
+                ],
+                Capture::Value(_) => vec![],
+            }))
+            .chain(variables.iter().flat_map(|(name, capture)| match capture {
+                Capture::Value(value) => vec![
+                    ident(&format!("_RUST_{name}")), punct('='), value.clone(), punct(';'),
+                ],
+                Capture::Mut(_) => vec![],
+            }))
         ),
-    ]))
+    ]);
+    if warnings.is_empty() {
+        Ok(block)
+    } else {
+        Ok(TokenStream::from_iter([braces(warnings.into_iter().chain(block))]))
+    }
+}
+
+/// Expand a `#[python_body]` function into the same function, with a body that runs the Python code.
+///
+/// The parameters are given to Python as arguments of a function with the same names,
+/// and the return value is converted to the return type (if any).
+fn python_body_impl(attr: TokenStream, item: TokenStream) -> Result<TokenStream, TokenStream> {
+    if let Some(token) = attr.into_iter().next() {
+        let span = token.span();
+        return Err(compile_error(
+            Some((span, span)),
+            "`#[python_body]` takes no arguments",
+        ));
+    }
+    let mut signature: Vec<TokenTree> = item.into_iter().collect();
+    let Some(TokenTree::Group(body)) = signature
+        .pop()
+        .filter(|t| matches!(t, TokenTree::Group(g) if g.delimiter() == Delimiter::Brace))
+    else {
+        return Err(compile_error(
+            None,
+            "`#[python_body]` can only be used on a function with a body",
+        ));
+    };
+    let Some(fn_index) = signature
+        .iter()
+        .position(|t| matches!(t, TokenTree::Ident(i) if i.to_string() == "fn"))
+    else {
+        return Err(compile_error(
+            None,
+            "`#[python_body]` can only be used on a function",
+        ));
+    };
+    let Some(params_index) = parameters_index(&signature, fn_index + 2) else {
+        return Err(compile_error(
+            None,
+            "expected the parameters of the function",
+        ));
+    };
+    let TokenTree::Group(params) = &signature[params_index] else {
+        unreachable!()
+    };
+    let params = parameter_names(params.stream())?;
+    // A `->` right after the parameters, rather than somewhere in a `where` clause.
+    let returns_value = matches!(
+        &signature[params_index + 1..],
+        [TokenTree::Punct(a), TokenTree::Punct(b), ..] if a.as_char() == '-' && b.as_char() == '>'
+    );
+    let block = python_impl(python_body_code(body.stream()), Kind::Body, &params)?;
+    let body = if returns_value {
+        block
+    } else {
+        // Without a return type, the return value of the Python function is ignored.
+        TokenStream::from_str("let _: ::inline_python::pyo3::PyObject =")
+            .unwrap()
+            .into_iter()
+            .chain(block)
+            .chain([punct(';')])
+            .collect()
+    };
+    let mut body = Group::new(Delimiter::Brace, body);
+    body.set_span(Span::call_site());
+    Ok(signature
+        .into_iter()
+        .chain([TokenTree::Group(body)])
+        .collect())
+}
+
+/// Find the parameter list of a function signature, given the index of the token after the name.
+///
+/// This skips over the generic parameters, which can contain parentheses themselves (e.g. `F: Fn(i32) -> i32`).
+fn parameters_index(signature: &[TokenTree], start: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut arrow = false;
+    for (i, t) in signature.iter().enumerate().skip(start) {
+        match t {
+            TokenTree::Punct(p) if p.as_char() == '<' => depth += 1,
+            // The `>` of a `->` doesn't close anything.
+            TokenTree::Punct(p) if p.as_char() == '>' && !arrow => depth = depth.checked_sub(1)?,
+            TokenTree::Group(g) if depth == 0 && g.delimiter() == Delimiter::Parenthesis => {
+                return Some(i);
+            }
+            _ => {}
+        }
+        arrow =
+            matches!(t, TokenTree::Punct(p) if p.as_char() == '-' && p.spacing() == Spacing::Joint);
+    }
+    None
+}
+
+/// The Python code of a `#[python_body]` function.
+///
+/// The body of the function must be valid Rust syntax before the attribute gets to see it,
+/// so it can be a `python! {..}` invocation, of which the contents are used.
+/// Otherwise, the body itself is used, which only works for very simple code, like `{ return x }`.
+fn python_body_code(body: TokenStream) -> TokenStream {
+    let tokens: Vec<TokenTree> = body.clone().into_iter().collect();
+    match &tokens[..] {
+        [
+            path @ ..,
+            TokenTree::Ident(name),
+            TokenTree::Punct(bang),
+            TokenTree::Group(code),
+        ] if name.to_string() == "python"
+            && bang.as_char() == '!'
+            && code.delimiter() == Delimiter::Brace
+            && path.iter().all(|t| match t {
+                TokenTree::Ident(_) => true,
+                TokenTree::Punct(p) => p.as_char() == ':',
+                _ => false,
+            }) =>
+        {
+            code.stream()
+        }
+        _ => body,
+    }
 }
 
+/// Get the names of the parameters, which must be of the form `name: Type` or `mut name: Type`.
+///
+/// Parameters named `_` are not given to Python.
+fn parameter_names(params: TokenStream) -> Result<Vec<Ident>, TokenStream> {
+    let mut names = Vec::new();
+    let mut tokens = params.into_iter().peekable();
+    while let Some(first) = tokens.next() {
+        let name = match first {
+            TokenTree::Ident(i) if i.to_string() == "mut" => tokens.next(),
+            token => Some(token),
+        };
+        let next = tokens.next();
+        match (name, next) {
+            (Some(TokenTree::Ident(name)), Some(TokenTree::Punct(colon)))
+                if colon.as_char() == ':' && name.to_string() != "self" =>
+            {
+                if name.to_string() != "_" {
+                    names.push(name);
+                }
+            }
+            (Some(TokenTree::Ident(name)), _) if name.to_string() == "self" => {
+                let span = name.span();
+                return Err(compile_error(
+                    Some((span, span)),
+                    "`#[python_body]` does not support methods",
+                ));
+            }
+            (name, _) => {
+                let span = name.map_or_else(Span::call_site, |t| t.span());
+                return Err(compile_error(
+                    Some((span, span)),
+                    "`#[python_body]` only supports parameters of the form `name: Type`",
+                ));
+            }
+        }
+        // Skip the type, up to the next `,` that is not within `<..>`.
+        let mut depth = 0;
+        let mut previous = None;
+        while let Some(token) =
+            tokens.next_if(|t| depth > 0 || !matches!(t, TokenTree::Punct(p) if p.as_char() == ','))
+        {
+            if let TokenTree::Punct(p) = &token {
+                match p.as_char() {
+                    '<' => depth += 1,
+                    // Not the `>` of `->`, as in `fn(i32) -> i32`.
+                    '>' if previous != Some('-') => depth -= 1,
+                    _ => {}
+                }
+            }
+            previous = match &token {
+                TokenTree::Punct(p) => Some(p.as_char()),
+                _ => None,
+            };
+        }
+        tokens.next(); // The `,`.
+    }
+    Ok(names)
+}
+
+/// The Python name of a parameter, which is the Rust name without any `r#`.
+fn param_name(param: &Ident) -> String {
+    let name = param.to_string();
+    name.strip_prefix("r#").map_or(name.clone(), str::to_string)
+}
+
+/// Parse (and remove) an `in context;` line at the start of the input.
+///
+/// The context expression is returned as a parenthesized group.
+fn parse_context(input: TokenStream) -> Result<(Option<Group>, TokenStream), TokenStream> {
+    let mut tokens = input.clone().into_iter();
+    let Some(TokenTree::Ident(keyword)) = tokens.next() else {
+        return Ok((None, input));
+    };
+    if keyword.to_string() != "in" {
+        return Ok((None, input));
+    }
+    let mut context = Vec::new();
+    for token in tokens.by_ref() {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == ';' => {
+                if context.is_empty() {
+                    return Err(compile_error(
+                        Some((p.span(), p.span())),
+                        "expected a context before `;`",
+                    ));
+                }
+                let mut group = Group::new(Delimiter::Parenthesis, TokenStream::from_iter(context));
+                group.set_span(keyword.span());
+                return Ok((Some(group), tokens.collect()));
+            }
+            token => context.push(token),
+        }
+    }
+    let span = keyword.span();
+    Err(compile_error(Some((span, span)), "expected `in context;`"))
+}
+
+/// Get the name of the function defined by the code,
+/// which must consist of a single `def` statement.
+fn function_name(
+    python: &CStr,
+    lines: &Lines,
+    directives: &Directives,
+) -> Result<String, TokenStream> {
+    Python::with_gil(|py| {
+        let filename = directives.filename();
+        let name = || -> PyResult<Option<String>> {
+            let ast = py.import("ast")?;
+            let body = ast
+                .call_method1("parse", (python.to_str().unwrap(), &filename))?
+                .getattr("body")?;
+            if body.len()? != 1 {
+                return Ok(None);
+            }
+            let def = body.get_item(0)?;
+            if !def.is_instance(&ast.getattr("FunctionDef")?)? {
+                return Ok(None);
+            }
+            Ok(Some(def.getattr("name")?.extract()?))
+        };
+        match name() {
+            Ok(Some(name)) => Ok(name),
+            Ok(None) => Err(compile_error(
+                lines.all(),
+                "expected a single `def` statement",
+            )),
+            Err(err) => Err(python_error_to_compile_error(py, err, &filename, lines)),
+        }
+    })
+}
+
+/// The `[(line, column), …]` list of where every line of the block starts in the Rust source.
+fn line_columns(lines: &Lines) -> TokenTree {
+    let tuples = lines.columns().flat_map(|(line, column)| {
+        [
+            TokenTree::Group(Group::new(
+                Delimiter::Parenthesis,
+                TokenStream::from_iter([
+                    TokenTree::Literal(Literal::usize_unsuffixed(line)),
+                    punct(','),
+                    TokenTree::Literal(Literal::usize_unsuffixed(column)),
+                ]),
+            )),
+            punct(','),
+        ]
+    });
+    TokenTree::Group(Group::new(
+        Delimiter::Bracket,
+        TokenStream::from_iter(tuples),
+    ))
+}
+
+/// Compile the code and marshal it.
+///
+/// If the code ends in an expression, this results in a tuple of two code objects:
+/// one for the statements and one for the final expression.
+/// Otherwise, it's just a single code object.
+///
+/// If `eval` is set, the code is required to end in an expression.
+///
+/// The Python warnings produced while compiling are returned as Rust code that produces compiler warnings.
 fn compile_to_bytecode(
     python: &CStr,
-    filename: &CStr,
-    tokens: TokenStream,
-) -> Result<Literal, TokenStream> {
+    lines: &Lines,
+    eval: bool,
+    directives: &Directives,
+    function: Option<&[String]>,
+) -> Result<(Literal, TokenStream), TokenStream> {
     Python::with_gil(|py| {
-        let compiled = compile_python(py, python, filename, tokens)?;
+        let start = Instant::now();
+        let (compiled, warnings) = record_warnings(py, &directives.filename(), || {
+            compile_python_with_value(py, python, lines, directives, function)
+        })
+        .map_err(|err| python_error_to_compile_error(py, err, &directives.filename(), lines))?;
+        let (code, value_code) = compiled?;
+        report_profile(&[("compiled", start.elapsed())]);
+        let compiled = match value_code {
+            Some(value_code) => PyTuple::new(py, [code, value_code])
+                .unwrap()
+                .into_any()
+                .unbind(),
+            None if eval => return Err(missing_value_error(lines)),
+            None => code,
+        };
         let bytes = unsafe {
             let ptr =
                 pyo3::ffi::PyMarshal_WriteObjectToString(compiled.as_ptr(), pyo3::marshal::VERSION);
             Py::from_owned_ptr(py, ptr)
         };
-        Ok(Literal::byte_string(bytes.as_bytes(py)))
+        let warnings = warnings
+            .into_iter()
+            .map(|warning| {
+                let spans = warning.line.and_then(|line| lines.get(line));
+                compile_warning(spans.map(|(span, _)| span), &warning.message)
+            })
+            .collect();
+        Ok((Literal::byte_string(bytes.as_bytes(py)), warnings))
     })
 }
+
+/// Create a compiler warning at the given span.
+///
+/// Proc macros can't emit warnings on stable Rust,
+/// so this uses a deprecated item, which results in a warning when used.
+fn compile_warning(span: Option<Span>, warning: &str) -> TokenStream {
+    fn set_spans(tokens: TokenStream, span: Span) -> TokenStream {
+        tokens
+            .into_iter()
+            .map(|mut token| {
+                if let TokenTree::Group(group) = &token {
+                    token = TokenTree::Group(Group::new(
+                        group.delimiter(),
+                        set_spans(group.stream(), span),
+                    ));
+                }
+                token.set_span(span);
+                token
+            })
+            .collect()
+    }
+    let note = Literal::string(&format!("python: {warning}"));
+    let tokens = TokenStream::from_str(&format!(
+        "const _: () = {{ #[deprecated(note = {note})] struct PythonWarning; let _ = PythonWarning; }};"
+    ))
+    .unwrap();
+    set_spans(tokens, span.unwrap_or_else(Span::call_site))
+}
@@ -2,13 +2,16 @@
 
 use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
 use pyo3::{
-    Bound, IntoPyObject, PyErr, PyResult, PyTypeInfo, Python, exceptions::PyBaseException,
-    prelude::*, types::PyTraceback,
+    Bound, IntoPyObject, PyErr, PyResult, PyTypeInfo, Python,
+    exceptions::PyBaseException,
+    prelude::*,
+    types::{IntoPyDict, PySlice, PyString, PyTraceback},
 };
 use std::{
     collections::BTreeMap,
-    ffi::CStr,
+    ffi::{CStr, CString},
     fmt::{Display, Write},
+    time::Duration,
 };
 
 /// Create a compile_error!{} using two spans that mark the start and end of the error.
@@ -43,17 +46,180 @@ pub(crate) fn string(s: &str) -> TokenTree {
     TokenTree::Literal(Literal::string(s))
 }
 
+/// How the Python code is compiled, selected with `//! mode: ..`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub(crate) enum Mode {
+    /// Statements, like a Python file.
+    ///
+    /// If the code ends in an expression, that expression is compiled
+    /// separately, such that its value can be used.
+    #[default]
+    Exec,
+    /// Statements, like in the interactive interpreter.
+    ///
+    /// The value of every expression statement is printed (through `sys.displayhook`).
+    Single,
+    /// A single expression.
+    Eval,
+}
+
+/// Options that can be given through `//! key: value` lines at the start of the Python code.
+pub(crate) struct Directives {
+    pub(crate) mode: Mode,
+    /// Whether the result may be reused for identical code. Only used by `ct_python`.
+    pub(crate) cache: bool,
+    /// The module name, used as `__name__` and as the file name in tracebacks.
+    pub(crate) module: Option<String>,
+    /// The seed for the `random` module.
+    pub(crate) seed: Option<u64>,
+    /// The optimization level, like Python's `-O` (1) and `-OO` (2) flags.
+    pub(crate) optimize: Option<u8>,
+    /// The directory (relative to the crate root) to add to `sys.path` instead of `python`, or `false`.
+    /// Only used by `ct_python`.
+    pub(crate) sys_path: Option<String>,
+    /// The maximum number of seconds the code may run. Only used by `ct_python`.
+    pub(crate) timeout: Option<u64>,
+    /// Whether the output is written to a file in `OUT_DIR` and included. Only used by `ct_python`.
+    pub(crate) output_file: bool,
+    /// The name under which the globals are put in `sys.modules` afterwards. Only used by `ct_python`.
+    pub(crate) export: Option<String>,
+}
+
+impl Default for Directives {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            cache: true,
+            module: None,
+            seed: None,
+            optimize: None,
+            sys_path: None,
+            timeout: None,
+            output_file: false,
+            export: None,
+        }
+    }
+}
+
+impl Directives {
+    /// The file name for the compiled code: the module name, or otherwise the Rust source file.
+    pub(crate) fn filename(&self) -> String {
+        match &self.module {
+            Some(module) => module.clone(),
+            None => Span::call_site().file(),
+        }
+    }
+}
+
+/// Parse (and remove) the `//! key: value` lines at the start of the input.
+pub(crate) fn parse_directives(
+    input: TokenStream,
+) -> Result<(Directives, TokenStream), TokenStream> {
+    /// A `//! ..` comment is turned into the tokens `#![doc = ".."]` by the Rust tokenizer.
+    fn doc_comment(
+        tokens: &mut (impl Iterator<Item = TokenTree> + Clone),
+    ) -> Option<(String, Span)> {
+        let mut lookahead = tokens.clone();
+        let (
+            Some(TokenTree::Punct(hash)),
+            Some(TokenTree::Punct(bang)),
+            Some(TokenTree::Group(group)),
+        ) = (lookahead.next(), lookahead.next(), lookahead.next())
+        else {
+            return None;
+        };
+        if hash.as_char() != '#' || bang.as_char() != '!' || group.delimiter() != Delimiter::Bracket
+        {
+            return None;
+        }
+        let mut attr = group.stream().into_iter();
+        let (Some(TokenTree::Ident(doc)), Some(TokenTree::Punct(_)), Some(TokenTree::Literal(lit))) =
+            (attr.next(), attr.next(), attr.next())
+        else {
+            return None;
+        };
+        if doc.to_string() != "doc" {
+            return None;
+        }
+        *tokens = lookahead;
+        Some((lit.to_string().trim_matches('"').to_string(), group.span()))
+    }
+
+    let mut directives = Directives::default();
+    let mut tokens = input.into_iter();
+    while let Some((doc, span)) = doc_comment(&mut tokens) {
+        let error = |msg: &str| Err(compile_error(Some((span, span)), msg));
+        let Some((key, value)) = doc.split_once(':') else {
+            return error("expected a directive of the form `//! key: value`");
+        };
+        match (key.trim(), value.trim()) {
+            ("mode", "exec") => directives.mode = Mode::Exec,
+            ("mode", "single") => directives.mode = Mode::Single,
+            ("mode", "eval") => directives.mode = Mode::Eval,
+            ("mode", _) => return error("expected `exec`, `single`, or `eval`"),
+            ("cache", "true") => directives.cache = true,
+            ("cache", "false") => directives.cache = false,
+            ("cache", _) => return error("expected `true` or `false`"),
+            ("module", "") => return error("expected a module name"),
+            ("module", name) => directives.module = Some(name.to_string()),
+            ("seed", seed) => match seed.parse() {
+                Ok(seed) => directives.seed = Some(seed),
+                Err(_) => return error("expected an unsigned integer"),
+            },
+            ("optimize", level @ ("0" | "1" | "2")) => directives.optimize = level.parse().ok(),
+            ("optimize", _) => return error("expected `0`, `1`, or `2`"),
+            ("sys_path", "") => return error("expected a directory or `false`"),
+            ("sys_path", dir) => directives.sys_path = Some(dir.to_string()),
+            ("timeout", secs) => match secs.parse() {
+                Ok(secs) => directives.timeout = Some(secs),
+                Err(_) => return error("expected a number of seconds"),
+            },
+            ("output", "inline") => directives.output_file = false,
+            ("output", "file") => directives.output_file = true,
+            ("output", _) => return error("expected `inline` or `file`"),
+            ("export", name) if !is_module_name(name) => return error("expected a module name"),
+            ("export", name) => directives.export = Some(name.to_string()),
+            (key, _) => return error(&format!("unknown directive `{key}`")),
+        }
+    }
+    Ok((directives, tokens.collect()))
+}
+
+/// Whether the name is a valid name for a top-level Python module.
+fn is_module_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// A Rust value captured by a `python!{}` block.
+#[allow(dead_code)] // ct-python doesn't capture anything.
+pub(crate) enum Capture {
+    /// `'var`, `'ref var`, or `'_(expr)`: the value (or reference) is given to Python.
+    Value(TokenTree),
+    /// `'mut var`: the variable is borrowed, and written back after the block.
+    Mut(Ident),
+}
+
 /// Turn the tokens into a string with reconstructed whitespace.
 ///
+/// Also returns the spans of the tokens on every line of the result.
+///
 /// If `variables` is set, variables (syntax: 'var) are replaced by `_RUST_var` and inserted in the map.
+/// Mutable variables (syntax: 'mut var) are also replaced by `_RUST_var`, but marked as [`Capture::Mut`].
+/// Borrowed variables (syntax: 'ref var) are inserted in the map as the expression `(&var)`.
+/// Expressions (syntax: '_(expr)) are replaced by `_RUST_00000`, `_RUST_00001`, etc.,
+/// and inserted in the map as a parenthesized group under the name `00000`, `00001`, etc.
+/// These can't collide with variable names, since Rust identifiers can't start with a digit.
 pub(crate) fn python_from_macro(
     input: TokenStream,
-    variables: Option<&mut BTreeMap<String, Ident>>,
-) -> Result<String, TokenStream> {
+    variables: Option<&mut BTreeMap<String, Capture>>,
+) -> Result<(String, Lines), TokenStream> {
     struct Location {
         first_indent: Option<usize>,
         line: usize,
         column: usize,
+        lines: Lines,
     }
 
     fn add_whitespace(
@@ -69,9 +235,18 @@ pub(crate) fn python_from_macro(
                 loc.line += 1;
             }
             let first_indent = *loc.first_indent.get_or_insert(column);
-            let indent = column.checked_sub(first_indent);
-            let indent =
-                indent.ok_or_else(|| compile_error(Some((span, span)), "invalid indent"))?;
+            let indent = column.checked_sub(first_indent).ok_or_else(|| {
+                let token = span.source_text().unwrap_or_default();
+                compile_error(
+                    Some((span, span)),
+                    &format!(
+                        "invalid indent: `{token}` on line {line} starts at column {column}, \
+                        but the first line of the block starts at column {first_indent}. \
+                        No line can start left of the first line; \
+                        try aligning the first statement with the leftmost line",
+                    ),
+                )
+            })?;
             for _ in 0..indent {
                 python.push(' ');
             }
@@ -89,12 +264,42 @@ pub(crate) fn python_from_macro(
         python: &mut String,
         loc: &mut Location,
         input: TokenStream,
-        mut variables: Option<&mut BTreeMap<String, Ident>>,
+        mut variables: Option<&mut BTreeMap<String, Capture>>,
     ) -> Result<(), TokenStream> {
-        let mut tokens = input.into_iter();
+        let mut tokens = input.into_iter().peekable();
         while let Some(token) = tokens.next() {
             let span = token.span();
+            let is_comment = matches!(&token, TokenTree::Punct(x) if x.as_char() == '#'
+                && !(x.spacing() == Spacing::Joint
+                    && matches!(tokens.peek(), Some(TokenTree::Punct(p)) if matches!(p.as_char(), '#' | '/'))));
+            if is_comment {
+                // A '#' comment. Skip the rest of the line, without adding any whitespace,
+                // since the indentation of a comment doesn't matter in Python.
+                let line = span.start().line();
+                while let Some(t) = tokens.next_if(|t| t.span().start().line() == line) {
+                    // A bracket or string that continues on the next line would take
+                    // the code on the next lines with it.
+                    if t.span().end().line() != line {
+                        let what = match t {
+                            TokenTree::Group(_) => "unbalanced bracket",
+                            _ => "multi-line string",
+                        };
+                        let (start, end) = (t.span().start(), t.span().end());
+                        return Err(compile_error(
+                            Some((start, start)),
+                            &format!(
+                                "{what} in `#` comment (which continues until line {})",
+                                end.line()
+                            ),
+                        ));
+                    }
+                }
+                continue;
+            }
             add_whitespace(python, loc, span)?;
+            if !matches!(token, TokenTree::Group(_)) {
+                loc.lines.add(python, loc.line, span);
+            }
             match &token {
                 TokenTree::Group(x) => {
                     let (start, end) = match x.delimiter() {
@@ -104,10 +309,12 @@ pub(crate) fn python_from_macro(
                         Delimiter::None => ("", ""),
                     };
                     add_whitespace(python, loc, x.span_open())?;
+                    loc.lines.add(python, loc.line, x.span_open());
                     python.push_str(start);
                     loc.column += start.len();
                     add_tokens(python, loc, x.stream(), variables.as_deref_mut())?;
                     add_whitespace(python, loc, x.span_close())?;
+                    loc.lines.add(python, loc.line, x.span_close());
                     python.push_str(end);
                     loc.column += end.len();
                 }
@@ -120,48 +327,148 @@ pub(crate) fn python_from_macro(
                             unreachable!()
                         };
                         let name = ident.to_string();
-                        write!(python, "_RUST_{name}").unwrap();
-                        loc.column += name.chars().count() + 1;
-                        variables.entry(name).or_insert(ident);
-                    } else if x.as_char() == '#' && x.spacing() == Spacing::Joint {
-                        // Convert '##' to '//', because otherwise it's
-                        // impossible to use the Python operators '//' and '//='.
-                        match tokens.next() {
-                            Some(TokenTree::Punct(ref p)) if p.as_char() == '#' => {
-                                python.push_str("//");
-                                loc.column += 2;
-                            }
-                            Some(TokenTree::Punct(p)) => {
-                                python.push(x.as_char());
-                                python.push(p.as_char());
-                                loc.column += 2;
-                            }
-                            _ => {
-                                unreachable!();
+                        if name == "_" {
+                            let group = match tokens.next() {
+                                Some(TokenTree::Group(g))
+                                    if g.delimiter() == Delimiter::Parenthesis =>
+                                {
+                                    g
+                                }
+                                _ => {
+                                    return Err(compile_error(
+                                        Some((span, ident.span())),
+                                        "expected a parenthesized expression after `'_`",
+                                    ));
+                                }
+                            };
+                            let n = variables
+                                .keys()
+                                .filter(|k| k.starts_with(|c: char| c.is_ascii_digit()))
+                                .count();
+                            // Zero-padded, so the map (and the generated assignments) keep the
+                            // expressions in source order, even with more than ten of them.
+                            let key = format!("{n:05}");
+                            write!(python, "_RUST_{key}").unwrap();
+                            loc.lines.add(python, loc.line, group.span_close());
+                            let end_span = group.span_close().end();
+                            loc.line = end_span.line();
+                            loc.column = end_span.column();
+                            variables.insert(key, Capture::Value(TokenTree::Group(group)));
+                        } else if name == "mut" || name == "ref" {
+                            let var = match tokens.next() {
+                                Some(TokenTree::Ident(var)) => var,
+                                _ => {
+                                    return Err(compile_error(
+                                        Some((span, ident.span())),
+                                        &format!("expected a variable name after `'{name}`"),
+                                    ));
+                                }
+                            };
+                            write!(python, "_RUST_{var}").unwrap();
+                            loc.lines.add(python, loc.line, var.span());
+                            let end_span = var.span().end();
+                            loc.line = end_span.line();
+                            loc.column = end_span.column();
+                            // A variable that is used both as 'var and 'mut var is captured mutably,
+                            // and one that is used as both 'var and 'ref var is captured by reference.
+                            if name == "mut" {
+                                variables.insert(var.to_string(), Capture::Mut(var));
+                            } else if !matches!(
+                                variables.get(&var.to_string()),
+                                Some(Capture::Mut(_))
+                            ) {
+                                let mut amp = Punct::new('&', Spacing::Alone);
+                                amp.set_span(var.span());
+                                let mut group = Group::new(
+                                    Delimiter::Parenthesis,
+                                    TokenStream::from_iter([
+                                        TokenTree::Punct(amp),
+                                        TokenTree::Ident(var.clone()),
+                                    ]),
+                                );
+                                group.set_span(var.span());
+                                variables.insert(
+                                    var.to_string(),
+                                    Capture::Value(TokenTree::Group(group)),
+                                );
                             }
+                        } else {
+                            write!(python, "_RUST_{name}").unwrap();
+                            loc.lines.add(python, loc.line, ident.span());
+                            loc.column += name.chars().count() + 1;
+                            variables
+                                .entry(name)
+                                .or_insert(Capture::Value(TokenTree::Ident(ident)));
                         }
+                    } else if x.as_char() == '#'
+                        && x.spacing() == Spacing::Joint
+                        && matches!(tokens.peek(), Some(TokenTree::Punct(p)) if matches!(p.as_char(), '#' | '/'))
+                    {
+                        // Convert '##' and '#/' to '//', because otherwise it's
+                        // impossible to use the Python operators '//' and '//='.
+                        // ('##=' and '#/=' become '//=', since the '=' directly follows.)
+                        tokens.next();
+                        python.push_str("//");
+                        loc.column += 2;
                     } else {
                         python.push(x.as_char());
                         loc.column += 1;
                     }
                 }
                 TokenTree::Ident(x) => {
-                    write!(python, "{x}").unwrap();
+                    let name = x.to_string();
+                    if variables.is_some()
+                        && let Some(var) = name.strip_prefix("_RUST_")
+                    {
+                        // This would silently refer to (or overwrite) a captured variable.
+                        return Err(compile_error(
+                            Some((span, span)),
+                            &format!(
+                                "names starting with `_RUST_` are reserved for captured Rust variables \
+                                (use `'{var}` to refer to the Rust variable `{var}`)"
+                            ),
+                        ));
+                    }
+                    python.push_str(&name);
                     let end_span = token.span().end();
                     loc.line = end_span.line();
                     loc.column = end_span.column();
                 }
                 TokenTree::Literal(x) => {
                     let s = x.to_string();
+                    let string_prefix = s.find(['"', '\'']).map(|i| s[..i].trim_end_matches('#'));
+                    if string_prefix.is_some_and(|p| p.starts_with('c')) {
+                        return Err(compile_error(
+                            Some((span, span)),
+                            "C string literals do not exist in Python",
+                        ));
+                    }
                     // Remove space in prefixed strings like `f ".."`.
                     // (`f".."` is not allowed in some versions+editions of Rust.)
-                    if s.starts_with('"')
-                        && python.ends_with(' ')
-                        && python[..python.len() - 1].ends_with(|c: char| c.is_ascii_alphabetic())
+                    let mut start = python.len();
+                    if let Some(string_prefix) = string_prefix
+                        && let Some(before) = python.strip_suffix(' ')
+                        && let rest = before.trim_end_matches(|c: char| c.is_alphanumeric())
+                        && is_string_prefix(&format!("{}{string_prefix}", &before[rest.len()..]))
                     {
+                        start = rest.len();
                         python.pop();
                     }
-                    python.push_str(&s);
+                    if let Some(prefix) = string_prefix.filter(|p| p.contains('r')) {
+                        let content = s[prefix.len()..].trim_matches('#');
+                        push_raw_string(python, prefix, &content[1..content.len() - 1]);
+                    } else if string_prefix.is_some() {
+                        push_with_unicode_escapes(python, &s);
+                    } else {
+                        python.push_str(&s);
+                    }
+                    if let Some(variables) = &mut variables
+                        && let Some(prefix_len) = python[start..].find(['"', '\''])
+                        && python[start..start + prefix_len].contains(['f', 'F', 't', 'T'])
+                    {
+                        let literal = add_format_string_captures(&python[start..], span, variables);
+                        python.replace_range(start.., &literal);
+                    }
                     let end_span = token.span().end();
                     loc.line = end_span.line();
                     loc.column = end_span.column();
@@ -176,94 +483,510 @@ pub(crate) fn python_from_macro(
         line: 1,
         column: 0,
         first_indent: None,
+        lines: Lines::default(),
     };
     add_tokens(&mut python, &mut location, input, variables)?;
-    Ok(python)
+    Ok((python, location.lines))
 }
 
-pub(crate) fn compile_python(
-    py: Python<'_>,
-    python: &CStr,
-    filename: &CStr,
-    tokens: TokenStream,
-) -> Result<Py<PyAny>, TokenStream> {
-    unsafe {
-        pyo3::PyObject::from_owned_ptr_or_err(
-            py,
-            pyo3::ffi::Py_CompileString(
-                python.as_ptr(),
-                filename.as_ptr(),
-                pyo3::ffi::Py_file_input,
-            ),
-        )
+/// The spans of the tokens that produced each line of the generated Python code.
+///
+/// Used to point errors and warnings in the Python code at the right tokens.
+/// For every line, this holds the (0-based) column in the Python code where each token starts,
+/// in order, together with the span of that token.
+#[derive(Default)]
+pub(crate) struct Lines(BTreeMap<usize, Vec<(usize, Span)>>);
+
+impl Lines {
+    /// Record that `span` produced the code that starts at the end of `python`, on `line`.
+    fn add(&mut self, python: &str, line: usize, span: Span) {
+        let line_start = python.rfind('\n').map_or(0, |i| i + 1);
+        let column = python[line_start..].chars().count();
+        self.0.entry(line).or_default().push((column, span));
+    }
+
+    /// The first and last span of the tokens that produced the given line.
+    pub(crate) fn get(&self, line: usize) -> Option<(Span, Span)> {
+        let tokens = self.0.get(&line)?;
+        Some((tokens.first()?.1, tokens.last()?.1))
+    }
+
+    /// The first and last span of the tokens that produced the (0-based) columns `start..end` of the given line.
+    ///
+    /// Without an `end`, this only gives the token at `start`.
+    pub(crate) fn get_columns(
+        &self,
+        line: usize,
+        start: usize,
+        end: Option<usize>,
+    ) -> Option<(Span, Span)> {
+        let tokens = self.0.get(&line)?;
+        let token_at = |column: usize| tokens.iter().rev().find(|&&(c, _)| c <= column);
+        let first = token_at(start).or(tokens.first())?;
+        let last = match end {
+            Some(end) if end > start => token_at(end - 1).unwrap_or(first),
+            _ => first,
+        };
+        Some((first.1, last.1))
+    }
+
+    /// The line and (1-based) column of the first token of every line.
+    #[allow(dead_code)] // ct-python doesn't run code after compilation.
+    pub(crate) fn columns(&self) -> impl Iterator<Item = (usize, usize)> {
+        self.0
+            .iter()
+            .map(|(&line, tokens)| (line, tokens[0].1.column()))
+    }
+
+    /// The first and last span of all the code.
+    pub(crate) fn all(&self) -> Option<(Span, Span)> {
+        let (_, first) = self.0.first_key_value()?;
+        let (_, last) = self.0.last_key_value()?;
+        Some((first.first()?.1, last.last()?.1))
     }
-    .map_err(|err| python_error_to_compile_error(py, err, tokens))
 }
 
-/// Format a nice error message for a python compilation error.
-pub(crate) fn python_error_to_compile_error(
-    py: Python,
-    error: PyErr,
-    tokens: TokenStream,
-) -> TokenStream {
-    /// Iterate recursively over all spans in a token stream.
-    fn for_all_spans(input: TokenStream, f: &mut impl FnMut(Span)) {
-        for token in input {
-            match token {
-                TokenTree::Group(group) => {
-                    f(group.span_open());
-                    for_all_spans(group.stream(), f);
-                    f(group.span_close());
-                }
-                _ => f(token.span()),
+/// Convert the Python code to a `CString`, or give an error pointing at the line with a NUL character.
+///
+/// A NUL can only end up in the code from a literal NUL character in the source,
+/// since escapes like `\0` are passed on to Python as they are.
+pub(crate) fn python_cstring(python: String, lines: &Lines) -> Result<CString, TokenStream> {
+    if let Some(nul) = python.find('\0') {
+        let line = 1 + python[..nul].matches('\n').count();
+        return Err(compile_error(
+            lines.get(line).or_else(|| lines.all()),
+            "the code contains a NUL character, which Python can't compile; \
+            use `\\0` in a (non-raw) string literal instead",
+        ));
+    }
+    Ok(CString::new(python).unwrap())
+}
+
+/// Push a (non-raw) string literal, converting Rust's `\u{..}` escapes to Python's `\U........`.
+fn push_with_unicode_escapes(python: &mut String, literal: &str) {
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            python.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') if chars.as_str().starts_with('{') => {
+                let (hex, rest) = chars.as_str()[1..].split_once('}').unwrap();
+                let code = u32::from_str_radix(&hex.replace('_', ""), 16).unwrap();
+                write!(python, "\\U{code:08x}").unwrap();
+                chars = rest.chars();
+            }
+            Some(c) => {
+                python.push('\\');
+                python.push(c);
             }
+            None => python.push('\\'),
         }
     }
+}
 
-    /// Get the first and last span for a specific line of input from a TokenStream.
-    fn spans_for_line(input: TokenStream, line: usize) -> Option<(Span, Span)> {
-        let mut spans = None;
-        for_all_spans(input, &mut |span| {
-            if span.start().line() == line {
-                spans.get_or_insert((span, span)).1 = span;
+/// Replace captured Rust variables (`'var`) in the replacement fields of an f-string.
+///
+/// Within the braces, a `'` followed by an identifier starts a capture,
+/// unless the identifier is directly followed by another `'`, like in `{d['key']}`.
+fn add_format_string_captures(
+    literal: &str,
+    span: Span,
+    variables: &mut BTreeMap<String, Capture>,
+) -> String {
+    let mut result = String::with_capacity(literal.len());
+    let mut depth = 0usize;
+    let mut i = 0;
+    while let Some(c) = literal[i..].chars().next() {
+        let rest = &literal[i + c.len_utf8()..];
+        match c {
+            // Escaped braces: `{{` and `}}`.
+            '{' | '}' if depth == 0 && rest.starts_with(c) => {
+                result.push(c);
+                i += 1;
             }
-        });
-        spans
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '\'' if depth > 0 => {
+                let len = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let name = &rest[..len];
+                if name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                    && !matches!(name, "_" | "mut" | "ref")
+                    && !rest[len..].starts_with('\'')
+                {
+                    write!(result, "_RUST_{name}").unwrap();
+                    variables.entry(name.to_string()).or_insert_with(|| {
+                        Capture::Value(TokenTree::Ident(Ident::new(name, span)))
+                    });
+                    i += 1 + len;
+                    continue;
+                }
+                // A string literal. Copy it as is, up to and including the closing quote.
+                let len = rest.find('\'').map_or(rest.len(), |n| n + 1);
+                result.push('\'');
+                result.push_str(&rest[..len]);
+                i += 1 + len;
+                continue;
+            }
+            _ => {}
+        }
+        result.push(c);
+        i += c.len_utf8();
+    }
+    result
+}
+
+/// Push a Rust raw string (like `r#"..."#`) as a Python string literal with the same content.
+///
+/// This uses the first kind of quotes that doesn't occur in the content, using triple quotes
+/// if it spans multiple lines. If none work, it becomes a regular (escaped) string instead.
+fn push_raw_string(python: &mut String, prefix: &str, content: &str) {
+    let trailing_backslashes = content.len() - content.trim_end_matches('\\').len();
+    let quotes: &[&str] = if content.contains(['\n', '\r']) {
+        &["\"\"\"", "'''"]
+    } else {
+        &["\"", "'", "\"\"\"", "'''"]
+    };
+    // A raw string can't end in an odd number of backslashes, since the last one would escape the quote.
+    if trailing_backslashes.is_multiple_of(2)
+        && let Some(quote) = quotes
+            .iter()
+            .find(|q| !content.contains(*q) && !content.ends_with(&q[..1]))
+    {
+        write!(python, "{prefix}{quote}{content}{quote}").unwrap();
+        return;
     }
+    python.push_str(&prefix.replace(['r', 'R'], ""));
+    python.push('"');
+    for c in content.chars() {
+        match c {
+            '\\' => python.push_str("\\\\"),
+            '"' => python.push_str("\\\""),
+            '\n' => python.push_str("\\n"),
+            '\r' => python.push_str("\\r"),
+            c => python.push(c),
+        }
+    }
+    python.push('"');
+}
+
+/// Check if the given string is a valid prefix for a Python string literal, like `f` or `rb`.
+fn is_string_prefix(prefix: &str) -> bool {
+    matches!(
+        prefix.to_ascii_lowercase().as_str(),
+        "r" | "u" | "b" | "f" | "t" | "br" | "rb" | "fr" | "rf" | "tr" | "rt"
+    )
+}
+
+/// Print the reconstructed Python code to stderr, if `INLINE_PYTHON_DUMP=1` is set.
+pub(crate) fn dump_python(python: &str) {
+    if std::env::var_os("INLINE_PYTHON_DUMP").is_none_or(|v| v != "1") {
+        return;
+    }
+    let site = Span::call_site();
+    let mut dump = format!("python code at {}:{}:\n", site.file(), site.line());
+    // Skip the empty lines that only serve to get the line numbers right.
+    for (i, line) in python.lines().enumerate().skip_while(|(_, l)| l.is_empty()) {
+        writeln!(dump, "{:5} | {line}", i + 1).unwrap();
+    }
+    eprint!("{dump}");
+}
+
+/// Print how long compiling (and running) a block took, if `INLINE_PYTHON_PROFILE=1` is set.
+///
+/// Every entry of `timings` is a step, like `("compiled", duration)`.
+pub(crate) fn report_profile(timings: &[(&str, Duration)]) {
+    if std::env::var_os("INLINE_PYTHON_PROFILE").is_none_or(|v| v != "1") {
+        return;
+    }
+    let site = Span::call_site();
+    let mut report = String::from("note: python:");
+    for (i, (step, duration)) in timings.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(report, "{sep} {step} in {:.1?}", duration).unwrap();
+    }
+    eprintln!(
+        "{report}\n  --> {}:{}:{}",
+        site.file(),
+        site.line(),
+        site.column()
+    );
+}
+
+/// Compile the code, splitting off the final expression (if any) to be evaluated separately.
+///
+/// With `function`, the code is instead the body of a function with those parameters,
+/// and the final expression is a call to that function with the `_RUST_` variables of the same names.
+pub(crate) fn compile_python_with_value(
+    py: Python<'_>,
+    python: &CStr,
+    lines: &Lines,
+    directives: &Directives,
+    function: Option<&[String]>,
+) -> Result<(Py<PyAny>, Option<Py<PyAny>>), TokenStream> {
+    let filename = directives.filename();
+    let compile = || -> PyResult<_> {
+        let ast = py.import("ast")?;
+        let builtin_compile = py.import("builtins")?.getattr("compile")?;
+        let kwargs = [("optimize", directives.optimize.map_or(-1, i32::from))].into_py_dict(py)?;
+        let compile = |code: Bound<PyAny>, mode: &str| -> PyResult<Py<PyAny>> {
+            Ok(builtin_compile
+                .call((code, filename.as_str(), mode), Some(&kwargs))?
+                .unbind())
+        };
+        let filename = filename.as_str();
+        let python = python.to_str().unwrap();
+        // Code that runs before the block itself.
+        let mut prologue = String::new();
+        if let Some(module) = &directives.module {
+            writeln!(prologue, "__name__ = {}", PyString::new(py, module).repr()?).unwrap();
+        }
+        if let Some(seed) = directives.seed {
+            writeln!(prologue, "__import__('random').seed({seed})").unwrap();
+        }
+        if directives.mode == Mode::Eval {
+            let tree = ast.call_method1("parse", (python, filename, "eval"))?;
+            let value_code = compile(tree, "eval")?;
+            let code = compile(PyString::new(py, &prologue).into_any(), "exec")?;
+            return Ok((code, Some(value_code)));
+        }
+        let tree = ast.call_method1("parse", (python, filename))?;
+        let body = tree.getattr("body")?;
+        let call = match function {
+            Some(params) => {
+                // The parser accepts `return` outside a function, so the body can be moved into one.
+                let def_source = format!("def _python_body({}): pass", params.join(", "));
+                let def = ast
+                    .call_method1("parse", (def_source, filename))?
+                    .getattr("body")?
+                    .get_item(0)?;
+                if let Ok(first) = body.get_item(0) {
+                    ast.call_method1("copy_location", (&def, first))?;
+                    def.setattr("body", &body)?;
+                }
+                tree.setattr("body", [&def])?;
+                let args: Vec<String> = params.iter().map(|p| format!("_RUST_{p}")).collect();
+                let call_source = format!("_python_body({})", args.join(", "));
+                Some(ast.call_method1("parse", (call_source, filename, "eval"))?)
+            }
+            None => None,
+        };
+        let body = tree.getattr("body")?;
+        let prologue = ast
+            .call_method1("parse", (prologue, filename))?
+            .getattr("body")?;
+        // body[0:0] = prologue
+        body.call_method1("__setitem__", (PySlice::new(py, 0, 0, 1), prologue))?;
+        if directives.mode == Mode::Single {
+            let tree = ast.getattr("Interactive")?.call1((body,))?;
+            return Ok((compile(tree, "single")?, None));
+        }
+        let value_code = match (call, body.get_item(-1)) {
+            (Some(call), _) => Some(compile(call, "eval")?),
+            (None, Ok(last)) if last.is_instance(&ast.getattr("Expr")?)? => {
+                body.call_method1("pop", (-1,))?;
+                let expr = ast
+                    .getattr("Expression")?
+                    .call1((last.getattr("value")?,))?;
+                Some(compile(expr, "eval")?)
+            }
+            _ => None,
+        };
+        let code = compile(tree, "exec")?;
+        Ok((code, value_code))
+    };
+    compile().map_err(|err| python_error_to_compile_error(py, err, &filename, lines))
+}
 
-    fn get_syntax_error_info(err: &Bound<'_, PyBaseException>) -> PyResult<(usize, String)> {
+/// Create the error for when the code was expected to end in an expression, but didn't.
+pub(crate) fn missing_value_error(lines: &Lines) -> TokenStream {
+    let span = lines.all().map(|(_, last)| last);
+    compile_error(
+        span.map(|s| (s, s)),
+        "expected the Python code to end in an expression",
+    )
+}
+
+/// Format a nice error message for a python compilation error.
+pub(crate) fn python_error_to_compile_error(
+    py: Python,
+    error: PyErr,
+    filename: &str,
+    lines: &Lines,
+) -> TokenStream {
+    let (spans, msg) = python_error_info(py, error, filename, lines);
+    compile_error(spans, &msg)
+}
+
+/// Get the message and the location in the source for a python error.
+///
+/// `filename` is the file name the code was compiled with,
+/// to recognize the frames of the traceback that are within the block.
+pub(crate) fn python_error_info(
+    py: Python,
+    error: PyErr,
+    filename: &str,
+    lines: &Lines,
+) -> (Option<(Span, Span)>, String) {
+    /// Get the line, message, and (0-based) start and end column of a `SyntaxError`.
+    ///
+    /// The columns are `None` if Python didn't give them.
+    /// If the error continues on another line, the end column is `usize::MAX`.
+    fn get_syntax_error_info(
+        err: &Bound<'_, PyBaseException>,
+    ) -> PyResult<(usize, String, Option<usize>, Option<usize>)> {
         let line: usize = err.getattr("lineno")?.extract()?;
         let msg: String = err.getattr("msg")?.extract()?;
-        Ok((line, msg))
+        let offset = |name| -> PyResult<Option<usize>> {
+            let offset: Option<usize> = err.getattr(name)?.extract().unwrap_or(None);
+            Ok(offset.and_then(|offset| offset.checked_sub(1)))
+        };
+        let start = offset("offset")?;
+        let end_line: Option<usize> = err.getattr("end_lineno")?.extract().unwrap_or(None);
+        let end = match end_line {
+            Some(end_line) if end_line != line => Some(usize::MAX),
+            _ => offset("end_offset")?,
+        };
+        Ok((line, msg, start, end))
     }
 
-    fn get_traceback_info(tb: &Bound<'_, PyTraceback>) -> PyResult<(String, usize)> {
-        let frame = tb.getattr("tb_frame")?;
-        let code = frame.getattr("f_code")?;
-        let file: String = code.getattr("co_filename")?.extract()?;
-        let line: usize = frame.getattr("f_lineno")?.extract()?;
-        Ok((file, line))
+    /// Get the line and function name of every frame in the traceback
+    /// that is in the given file, outermost first.
+    fn get_traceback_info(
+        tb: &Bound<'_, PyTraceback>,
+        file: &str,
+    ) -> PyResult<Vec<(usize, String)>> {
+        let mut frames = Vec::new();
+        let mut tb = tb.clone().into_any();
+        while !tb.is_none() {
+            let code = tb.getattr("tb_frame")?.getattr("f_code")?;
+            if code.getattr("co_filename")?.extract::<String>()? == file {
+                let line: usize = tb.getattr("tb_lineno")?.extract()?;
+                frames.push((line, code.getattr("co_name")?.extract()?));
+            }
+            tb = tb.getattr("tb_next")?;
+        }
+        Ok(frames)
     }
 
     let value = (&error).into_pyobject(py).unwrap();
 
     if value.is_none() {
-        compile_error(None, &error.get_type(py).name().unwrap())
+        (None, error.get_type(py).name().unwrap().to_string())
     } else if let Ok(true) = error.matches(py, pyo3::exceptions::PySyntaxError::type_object(py))
-        && let Ok((line, msg)) = get_syntax_error_info(&value)
-        && let Some(spans) = spans_for_line(tokens.clone(), line)
+        && let Ok((line, mut msg, start, end)) = get_syntax_error_info(&value)
+        && let Some(spans) = lines.get(line)
     {
-        compile_error(Some(spans), &msg)
+        if let Some(hint) = syntax_error_hint(&msg, spans) {
+            write!(msg, "\n\nhelp: {hint}").unwrap();
+        }
+        let columns = start.and_then(|start| lines.get_columns(line, start, end));
+        (Some(columns.unwrap_or(spans)), msg)
     } else if let Some(tb) = &error.traceback(py)
-        && let Ok((file, line)) = get_traceback_info(tb)
-        && file == Span::call_site().file()
-        && let Some(spans) = spans_for_line(tokens, line)
+        && let Ok(frames) = get_traceback_info(tb, filename)
+        && let Some(&(line, _)) = frames.last()
+        && let Some(spans) = lines.get(line)
         && let Ok(msg) = value.str()
     {
-        compile_error(Some(spans), &msg)
+        // Point at the innermost line within this block,
+        // but show all the lines within this block that led there.
+        let mut msg = msg.to_string();
+        if frames.len() > 1 {
+            msg.push_str("\n\ntraceback:");
+            for (line, name) in frames {
+                write!(msg, "\n  line {line}, in {name}").unwrap();
+            }
+        }
+        (Some(spans), msg)
     } else if let Ok(msg) = value.str() {
-        compile_error(None, &msg)
+        (None, msg.to_string())
+    } else {
+        (None, error.get_type(py).name().unwrap().to_string())
+    }
+}
+
+/// Get a hint for a `SyntaxError` that is likely caused by a difference between Rust and Python syntax.
+///
+/// `spans` are the first and last token on the line with the error.
+fn syntax_error_hint(msg: &str, (first, last): (Span, Span)) -> Option<&'static str> {
+    let line = first.start().line();
+    let source = std::fs::read_to_string(first.local_file()?).ok()?;
+    let text = source.lines().nth(line - 1)?;
+    // Rust comments are not tokens, so they can only appear after the last token on the line.
+    let after_last = match last.end() {
+        end if end.line() == line => text.chars().skip(end.column() - 1).collect::<String>(),
+        _ => String::new(),
+    };
+    if after_last.trim_start().starts_with("//") {
+        Some(
+            "`//` starts a Rust comment, so the rest of the line is not part of the Python code; \
+            use `#/` (or `##`) for Python's `//` operator",
+        )
+    } else if msg.starts_with("unterminated string literal") && text.contains('\'') {
+        Some(
+            "strings in single quotes are not possible, since Rust only allows a single character within `'`; \
+            use double quotes, or `'var` to refer to a Rust variable within `python!{}`",
+        )
     } else {
-        compile_error(None, &error.get_type(py).name().unwrap())
+        None
+    }
+}
+
+/// A Python warning.
+pub(crate) struct Warning {
+    /// The line that caused the warning, if it is within the block.
+    pub(crate) line: Option<usize>,
+    pub(crate) message: String,
+}
+
+/// Run `f`, while recording the Python warnings instead of printing them.
+///
+/// Returns the result of `f` and the warnings.
+pub(crate) fn record_warnings<T>(
+    py: Python,
+    filename: &str,
+    f: impl FnOnce() -> T,
+) -> PyResult<(T, Vec<Warning>)> {
+    let warnings = py.import("warnings")?;
+    let catcher = warnings.call_method(
+        "catch_warnings",
+        (),
+        Some(&[("record", true)].into_py_dict(py)?),
+    )?;
+    let records = catcher.call_method0("__enter__")?;
+    let exit = ExitCatcher(catcher);
+    // Show every warning once per location, including the ones ignored by default (like DeprecationWarning).
+    warnings.call_method1("simplefilter", ("default",))?;
+    let result = f();
+    drop(exit);
+    let mut recorded = Vec::new();
+    for record in records.try_iter()? {
+        let record = record?;
+        let line = if record.getattr("filename")?.extract::<String>()? == filename {
+            Some(record.getattr("lineno")?.extract()?)
+        } else {
+            None
+        };
+        let category = record.getattr("category")?.getattr("__name__")?;
+        let message = format!("{category}: {}", record.getattr("message")?.str()?);
+        recorded.push(Warning { line, message });
+    }
+    Ok((result, recorded))
+}
+
+/// Exits the `warnings.catch_warnings()` context when dropped,
+/// so the original filters are restored even if something fails or panics.
+struct ExitCatcher<'py>(Bound<'py, PyAny>);
+
+impl Drop for ExitCatcher<'_> {
+    fn drop(&mut self) {
+        let py = self.0.py();
+        // There's nothing better to do with an error here than to ignore it.
+        let _ = self
+            .0
+            .call_method1("__exit__", (py.None(), py.None(), py.None()));
     }
 }
@@ -0,0 +1,115 @@
+use pyo3::{
+    Bound, FromPyObject, IntoPyObject, IntoPyObjectExt, Py, PyAny, PyErr, PyResult, Python,
+    exceptions::PyTypeError,
+    prelude::*,
+    types::{PyCFunction, PyTuple},
+};
+use std::marker::PhantomData;
+
+/// A Rust function or closure, passed to Python as a callable.
+///
+/// This lets the Python code call back into Rust.
+/// The arguments are converted with [`FromPyObject`],
+/// and the return value with [`IntoPyObject`].
+///
+/// ```
+/// # use inline_python::{PyCallback, python};
+/// let offset = 10;
+/// let add = PyCallback::new(move |a: i32, b: i32| a + b + offset);
+/// let greet = PyCallback::new(|name: String| format!("Hello, {name}!"));
+/// python! {
+///     assert 'add(1, 2) == 13
+///     assert 'greet("Python") == "Hello, Python!"
+///     assert list(map('add, [1, 2], [3, 4])) == [14, 16]
+/// }
+/// ```
+///
+/// Since Python can keep a reference to the callable (e.g. in a global
+/// variable of a [`Context`](crate::Context)), the function must be `Send + 'static`.
+/// Functions with up to eight arguments are supported.
+///
+/// Calling it with the wrong number or types of arguments, or with keyword arguments,
+/// raises a `TypeError`. A panic in the function is raised as a `PanicException`.
+pub struct PyCallback<F, Args>(F, PhantomData<fn(Args)>);
+
+impl<F: Callback<Args>, Args> PyCallback<F, Args> {
+    /// Wrap a function to pass it to Python as a callable.
+    pub fn new(f: F) -> Self {
+        Self(f, PhantomData)
+    }
+}
+
+impl<'py, F: Callback<Args>, Args> IntoPyObject<'py> for PyCallback<F, Args> {
+    type Target = PyCFunction;
+    type Output = Bound<'py, PyCFunction>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyCFunction>> {
+        let f = self.0;
+        PyCFunction::new_closure(py, None, None, move |args, kwargs| {
+            if kwargs.is_some_and(|kwargs| !kwargs.is_empty()) {
+                return Err(PyTypeError::new_err(
+                    "a Rust callback does not take keyword arguments",
+                ));
+            }
+            f.call(args)
+        })
+    }
+}
+
+/// A function that can be wrapped in a [`PyCallback`].
+///
+/// This is implemented for functions and closures with up to eight arguments
+/// that implement [`FromPyObject`], and a return type that implements [`IntoPyObject`].
+pub trait Callback<Args>: Send + 'static {
+    #[doc(hidden)]
+    fn call(&self, args: &Bound<PyTuple>) -> PyResult<Py<PyAny>>;
+}
+
+fn check_arg_count(args: &Bound<PyTuple>, n: usize) -> PyResult<()> {
+    if args.len() != n {
+        return Err(PyTypeError::new_err(format!(
+            "a Rust callback taking {n} argument(s) was called with {}",
+            args.len()
+        )));
+    }
+    Ok(())
+}
+
+impl<F, R> Callback<()> for F
+where
+    F: Fn() -> R + Send + 'static,
+    R: for<'py> IntoPyObject<'py>,
+{
+    fn call(&self, args: &Bound<PyTuple>) -> PyResult<Py<PyAny>> {
+        check_arg_count(args, 0)?;
+        self().into_py_any(args.py())
+    }
+}
+
+macro_rules! impl_callback {
+    ($($arg:ident)*) => {
+        impl<F, R, $($arg,)*> Callback<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + 'static,
+            R: for<'py> IntoPyObject<'py>,
+            $($arg: for<'py> FromPyObject<'py>,)*
+        {
+            #[allow(non_snake_case)]
+            fn call(&self, args: &Bound<PyTuple>) -> PyResult<Py<PyAny>> {
+                check_arg_count(args, [$(stringify!($arg)),*].len())?;
+                let ($($arg,)*) = args.extract::<($($arg,)*)>()?;
+                self($($arg),*).into_py_any(args.py())
+            }
+        }
+    };
+}
+
+impl_callback!(A);
+impl_callback!(A B);
+impl_callback!(A B C);
+impl_callback!(A B C D);
+impl_callback!(A B C D E);
+impl_callback!(A B C D E G);
+impl_callback!(A B C D E G H);
+impl_callback!(A B C D E G H I);
@@ -0,0 +1,20 @@
+use pyo3::{Py, PyAny};
+
+/// Python code from a string, compiled once by [`Context::compile`](crate::Context::compile)
+/// to be run many times.
+///
+/// ```
+/// # use inline_python::Context;
+/// let c = Context::new();
+/// let square = Context::compile("x * x").unwrap();
+/// for i in 0..5 {
+///     c.set("x", i);
+///     assert_eq!(c.eval_compiled::<i32>(&square).unwrap(), i * i);
+/// }
+/// ```
+///
+/// The compiled code is not tied to a context,
+/// and can be run in any context, or from multiple threads.
+pub struct CompiledSource {
+    pub(crate) code: Py<PyAny>,
+}
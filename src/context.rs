@@ -1,10 +1,12 @@
-use crate::PythonBlock;
 use crate::run::run_python_code;
+use crate::{Code, CompiledSource, InlinePythonError, PythonBlock, SetVars};
 use pyo3::{
     FromPyObject, IntoPyObject, Py, PyResult, Python,
+    exceptions::{PyNameError, PySyntaxError, PyValueError},
     prelude::*,
-    types::{PyCFunction, PyDict},
+    types::{PyByteArray, PyBytes, PyCFunction, PyDict, PyString, PyTuple},
 };
+use std::{ffi::CString, fmt::Write};
 
 /// An execution context for Python code.
 ///
@@ -38,6 +40,47 @@ use pyo3::{
 ///
 /// assert_eq!(c.get::<i32>("foo"), 15);
 /// ```
+///
+/// ## Threads
+///
+/// Running Python code holds the GIL (the global interpreter lock) for the whole block.
+/// Still, a long running block does not lock out other threads:
+/// the interpreter hands the GIL over to a waiting thread at regular intervals
+/// (every 5ms by default, see `sys.setswitchinterval`),
+/// in between two bytecode instructions.
+/// Other threads can even use the same context while a block is running.
+///
+/// This means the globals may change while a block is running,
+/// just like with Python threads.
+///
+/// A `Context` is `Send` and `Sync`: it only holds a reference to the globals
+/// dictionary, which doesn't need the GIL to be moved or shared,
+/// and every method acquires the GIL itself.
+/// It can be moved to another thread, or shared between threads using an `Arc`:
+///
+/// ```
+/// # use inline_python::{Context, python};
+/// use std::sync::Arc;
+///
+/// let c = Arc::new(Context::new());
+/// let threads: Vec<_> = (0..4)
+///     .map(|i| {
+///         let c = c.clone();
+///         std::thread::spawn(move || {
+///             c.run(python! {
+///                 results = globals().setdefault("results", [])
+///                 results.append('i)
+///             })
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(c.get::<Vec<i32>>("results").len(), 4);
+/// ```
+///
+/// Since the threads take turns holding the GIL, this does not make Python code run in parallel.
 pub struct Context {
     pub(crate) globals: Py<PyDict>,
 }
@@ -52,6 +95,80 @@ impl Context {
         Python::with_gil(Self::new_with_gil)
     }
 
+    /// Create a new context, and seed Python's `random` module.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c = Context::with_seed(1234);
+    /// c.run(python! {
+    ///     import random
+    ///     a = random.random()
+    /// });
+    ///
+    /// let d = Context::with_seed(1234);
+    /// d.run(python! {
+    ///     import random
+    ///     b = random.random()
+    /// });
+    ///
+    /// assert_eq!(c.get::<f64>("a"), d.get::<f64>("b"));
+    /// ```
+    ///
+    /// Note that there is only one `random` module, shared by all contexts.
+    /// Seeding it affects all Python code that uses it afterwards.
+    /// Other sources of nondeterminism, like the randomized hashing of strings
+    /// (`PYTHONHASHSEED`), can't be changed after Python is started.
+    ///
+    /// This function panics if it fails to create the context.
+    #[track_caller]
+    pub fn with_seed(seed: u64) -> Self {
+        Python::with_gil(|py| {
+            let context = Self::new_with_gil(py);
+            let random = py.import("random");
+            if let Err(err) = random.and_then(|random| random.call_method1("seed", (seed,))) {
+                panic!("{}", panic_string(py, &err));
+            }
+            context
+        })
+    }
+
+    /// Create a context that uses the globals of Python's `__main__` module directly.
+    ///
+    /// Unlike [`Context::new`], this doesn't copy anything: every context made
+    /// with `new_shared` uses the same global variables, which are also the ones that
+    /// `import __main__` gives access to.
+    /// Variables defined in one of them are visible in all others.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c = Context::new_shared();
+    /// c.run(python! {
+    ///     shared_example = 5
+    /// });
+    ///
+    /// let d = Context::new_shared();
+    /// d.run(python! {
+    ///     import __main__
+    ///     assert shared_example == 5
+    ///     assert __main__.shared_example == 5
+    /// });
+    /// ```
+    ///
+    /// This trades isolation for not having to copy the (usually small) dictionary.
+    /// Only use it if nothing else depends on the globals of `__main__`,
+    /// or if sharing them is the point.
+    /// (Note that [`Context::new`] copies `__main__` at the time it is called,
+    /// so variables defined in a shared context show up in contexts created afterwards.)
+    ///
+    /// This function panics if `__main__` can't be imported.
+    #[track_caller]
+    pub fn new_shared() -> Self {
+        Python::with_gil(|py| match py.import("__main__") {
+            Ok(main) => Self::from_bound_globals(&main.dict()),
+            Err(err) => panic!("{}", panic_string(py, &err)),
+        })
+    }
+
     #[track_caller]
     pub(crate) fn new_with_gil(py: Python) -> Self {
         match Self::try_new(py) {
@@ -66,11 +183,141 @@ impl Context {
         })
     }
 
+    /// Create a context that runs code with the given dictionary as its globals.
+    ///
+    /// The dictionary is not copied: it is shared with whatever else holds a reference to it.
+    /// Anything the Python code defines shows up in the dictionary, and anything
+    /// added to the dictionary by other code is visible to the Python code.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// use inline_python::pyo3::{prelude::*, types::PyDict};
+    ///
+    /// let globals = Python::with_gil(|py| {
+    ///     let globals = PyDict::new(py);
+    ///     globals.set_item("x", 5).unwrap();
+    ///     globals.unbind()
+    /// });
+    ///
+    /// let c = Python::with_gil(|py| Context::from_globals(globals.clone_ref(py)));
+    /// c.run(python! {
+    ///     y = x + 1
+    /// });
+    ///
+    /// Python::with_gil(|py| {
+    ///     let y: i32 = globals.bind(py).get_item("y").unwrap().unwrap().extract().unwrap();
+    ///     assert_eq!(y, 6);
+    /// });
+    /// ```
+    ///
+    /// Like any [`Py`], the dictionary can be passed in without holding the GIL.
+    /// The context acquires the GIL whenever it uses the dictionary.
+    ///
+    /// If the dictionary has no `__builtins__`, the code uses the builtins of the interpreter.
+    pub fn from_globals(globals: Py<PyDict>) -> Self {
+        Self { globals }
+    }
+
+    /// Create a context that runs code with the given dictionary as its globals.
+    ///
+    /// This is the same as [`Context::from_globals`], for a dictionary that is borrowed while holding the GIL.
+    /// The context holds its own reference to the same dictionary.
+    pub fn from_bound_globals(globals: &Bound<PyDict>) -> Self {
+        Self {
+            globals: globals.clone().unbind(),
+        }
+    }
+
+    /// Create a new context with a copy of the global variables of this context.
+    ///
+    /// Assigning to a global variable in one context does not affect the other.
+    ///
+    /// Note that this is a shallow copy: the values themselves are shared between
+    /// both contexts. This means that imported modules are shared, but also that
+    /// mutating a shared object (e.g. appending to a list) is visible in both contexts.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     foo = 5
+    /// };
+    ///
+    /// let d = c.fork();
+    ///
+    /// d.run(python! {
+    ///     foo = 6
+    /// });
+    ///
+    /// assert_eq!(c.get::<i32>("foo"), 5);
+    /// assert_eq!(d.get::<i32>("foo"), 6);
+    /// ```
+    ///
+    /// This function panics if it fails to copy the globals.
+    #[track_caller]
+    pub fn fork(&self) -> Self {
+        Python::with_gil(|py| match self.globals.bind(py).copy() {
+            Ok(globals) => Self {
+                globals: globals.unbind(),
+            },
+            Err(err) => panic!("{}", panic_string(py, &err)),
+        })
+    }
+
+    /// Copy all global variables from another context into this one.
+    ///
+    /// Variables that exist in both contexts are overwritten by the ones from `other`.
+    /// The `__dunder__` names that Python defines by itself, such as `__name__`, are skipped.
+    ///
+    /// Just like [`Context::fork`], this is a shallow copy.
+    /// The GIL is only acquired once.
+    ///
+    /// This function panics if it fails to copy the globals.
+    #[track_caller]
+    pub fn update_from(&self, other: &Context) {
+        Python::with_gil(|py| {
+            let globals = self.globals.bind(py);
+            for (key, value) in other.globals.bind(py) {
+                if key.extract::<&str>().is_ok_and(is_dunder) {
+                    continue;
+                }
+                if let Err(err) = globals.set_item(key, value) {
+                    panic!("{}", panic_string(py, &err));
+                }
+            }
+        })
+    }
+
     /// Get the globals as dictionary.
+    ///
+    /// The returned [`Py<PyDict>`] does not hold on to the GIL.
+    /// Use [`Py::bind`] with a [`Python`] token to use it,
+    /// or use [`Context::with_globals`].
     pub fn globals(&self) -> &Py<PyDict> {
         &self.globals
     }
 
+    /// Run a closure with access to the globals dictionary.
+    ///
+    /// The GIL is held for the duration of the closure.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// use inline_python::pyo3::prelude::*;
+    ///
+    /// let c = Context::new();
+    ///
+    /// c.with_globals(|globals| {
+    ///     globals.set_item("x", 5).unwrap();
+    /// });
+    ///
+    /// c.run(python! {
+    ///     assert x == 5
+    /// });
+    /// ```
+    pub fn with_globals<R>(&self, f: impl FnOnce(&Bound<PyDict>) -> R) -> R {
+        Python::with_gil(|py| f(self.globals.bind(py)))
+    }
+
     /// Retrieve a global variable from the context.
     ///
     /// This function panics if the variable doesn't exist, or the conversion fails.
@@ -89,17 +336,360 @@ impl Context {
         })
     }
 
-    /// Set a global variable in the context.
+    /// Retrieve a global `bytes` or `bytearray` variable from the context.
     ///
-    /// This function panics if the conversion fails.
-    pub fn set<T: for<'p> IntoPyObject<'p>>(&self, name: &str, value: T) {
+    /// This copies the data in one go, while `get::<Vec<u8>>()` converts
+    /// the value byte by byte, like any other sequence.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     data = bytes(range(256))
+    /// };
+    ///
+    /// assert_eq!(c.get_bytes("data"), (0..=255).collect::<Vec<u8>>());
+    /// ```
+    ///
+    /// This function panics if the variable doesn't exist, or is not `bytes` or `bytearray`.
+    pub fn get_bytes(&self, name: &str) -> Vec<u8> {
+        Python::with_gil(|py| match self.globals.bind(py).get_item(name) {
+            Err(_) | Ok(None) => {
+                panic!("Python context does not contain a variable named `{name}`",)
+            }
+            Ok(Some(value)) => {
+                if let Ok(bytes) = value.downcast::<PyBytes>() {
+                    bytes.as_bytes().to_vec()
+                } else if let Ok(bytes) = value.downcast::<PyByteArray>() {
+                    bytes.to_vec()
+                } else {
+                    panic!(
+                        "Unable to convert `{name}` to bytes: it is a `{ty}`",
+                        ty = value
+                            .get_type()
+                            .name()
+                            .map_or("?".into(), |n| n.to_string()),
+                    )
+                }
+            }
+        })
+    }
+
+    /// Retrieve a global variable from the context by converting it to JSON, using `serde`.
+    ///
+    /// The value is converted to JSON with `json.dumps`, and then deserialized into `T`.
+    /// This makes it possible to get any type that implements [`serde::Deserialize`]
+    /// from Python, without implementing [`FromPyObject`] for it.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// #[derive(serde::Deserialize, Debug, PartialEq)]
+    /// struct Point {
+    ///     x: i32,
+    ///     y: i32,
+    /// }
+    ///
+    /// let c: Context = python! {
+    ///     points = [{"x": 1, "y": 2}, {"x": 3, "y": 4}]
+    /// };
+    ///
+    /// let points = c.get_json::<Vec<Point>>("points");
+    /// assert_eq!(points, [Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+    /// ```
+    ///
+    /// This function panics if the variable doesn't exist, can't be converted to JSON,
+    /// or the deserialization fails.
+    ///
+    /// This requires the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn get_json<T: serde::de::DeserializeOwned>(&self, name: &str) -> T {
         Python::with_gil(|py| {
-            if let Err(e) = self.globals().bind(py).set_item(name, value) {
+            let Ok(Some(value)) = self.globals.bind(py).get_item(name) else {
+                panic!("Python context does not contain a variable named `{name}`")
+            };
+            let json = py
+                .import("json")
+                .and_then(|json| json.getattr("dumps")?.call1((value,))?.extract::<String>())
+                .unwrap_or_else(|e| panic!("Unable to convert `{name}` to JSON: {e}"));
+            serde_json::from_str(&json).unwrap_or_else(|e| {
                 panic!(
-                    "Unable to set `{name}` from a `{ty}`: {e}",
+                    "Unable to convert `{name}` to `{ty}`: {e}",
                     ty = std::any::type_name::<T>(),
-                );
-            }
+                )
+            })
+        })
+    }
+
+    /// Retrieve a numpy array (or anything else that supports the buffer protocol) from the context.
+    ///
+    /// The elements are copied into a `Vec` in C (row-major) order, and returned together with the shape.
+    /// This works for arrays that are not contiguous, like a slice of a larger array, as well.
+    ///
+    /// ```no_run
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     import numpy
+    ///     a = numpy.arange(6, dtype=numpy.int32).reshape(2, 3)
+    /// };
+    ///
+    /// let (data, shape) = c.get_numpy::<i32>("a");
+    /// assert_eq!(data, [0, 1, 2, 3, 4, 5]);
+    /// assert_eq!(shape, [2, 3]);
+    /// ```
+    ///
+    /// This function panics if the variable doesn't exist,
+    /// doesn't support the buffer protocol, or its element type isn't `T`.
+    ///
+    /// This requires the `numpy` feature.
+    /// The array can be turned into an `ndarray` with `Array::from_shape_vec(shape, data)`.
+    #[cfg(feature = "numpy")]
+    pub fn get_numpy<T: pyo3::buffer::Element + Copy>(&self, name: &str) -> (Vec<T>, Vec<usize>) {
+        Python::with_gil(|py| {
+            let Ok(Some(value)) = self.globals.bind(py).get_item(name) else {
+                panic!("Python context does not contain a variable named `{name}`")
+            };
+            pyo3::buffer::PyBuffer::<T>::get(&value)
+                .and_then(|buffer| Ok((buffer.to_vec(py)?, buffer.shape().to_vec())))
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "Unable to convert `{name}` to an array of `{ty}`: {e}",
+                        ty = std::any::type_name::<T>(),
+                    )
+                })
+        })
+    }
+
+    /// Retrieve a global variable from the context, without panicking.
+    ///
+    /// Like [`Context::get`], but returns an error if the variable doesn't
+    /// exist or the conversion fails.
+    pub fn try_get<T: for<'p> FromPyObject<'p>>(&self, name: &str) -> PyResult<T> {
+        Python::with_gil(|py| self.get_with_gil(py, name))
+    }
+
+    /// Retrieve a global variable from the context, or `default` if that fails.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     verbose = True
+    ///     level = "high"
+    /// };
+    ///
+    /// assert_eq!(c.get_or("verbose", false), true);
+    /// assert_eq!(c.get_or("retries", 3), 3);
+    /// assert_eq!(c.get_or("level", 1), 1);
+    /// ```
+    ///
+    /// The default is used both if the variable doesn't exist,
+    /// and if it exists but can't be converted to `T` (like `level` above).
+    /// Use [`Context::try_get`] to tell those apart:
+    /// a missing variable results in a `NameError`.
+    pub fn get_or<T: for<'p> FromPyObject<'p>>(&self, name: &str, default: T) -> T {
+        self.try_get(name).unwrap_or(default)
+    }
+
+    /// Retrieve a global variable from the context, or compute a default if that fails.
+    ///
+    /// Like [`Context::get_or`], but only calls `default` when it is needed.
+    pub fn get_or_else<T: for<'p> FromPyObject<'p>>(
+        &self,
+        name: &str,
+        default: impl FnOnce() -> T,
+    ) -> T {
+        self.try_get(name).unwrap_or_else(|_| default())
+    }
+
+    /// Retrieve a global variable from the context, or `T::default()` if that fails.
+    ///
+    /// Like [`Context::get_or`], with [`Default::default`] as the default.
+    pub fn get_or_default<T: for<'p> FromPyObject<'p> + Default>(&self, name: &str) -> T {
+        self.try_get(name).unwrap_or_default()
+    }
+
+    /// Retrieve several global variables of the same type from the context.
+    ///
+    /// The GIL is only acquired once for all variables.
+    pub fn get_all<T: for<'p> FromPyObject<'p>>(&self, names: &[&str]) -> PyResult<Vec<T>> {
+        Python::with_gil(|py| {
+            names
+                .iter()
+                .map(|name| self.get_with_gil(py, name))
+                .collect()
+        })
+    }
+
+    /// Retrieve several global variables of different types from the context, as a tuple.
+    ///
+    /// The GIL is only acquired once for all variables.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     a = 1
+    ///     b = "two"
+    ///     c = 3.0
+    /// };
+    ///
+    /// let (a, b, c) = c.get_tuple::<(i32, String, f64)>(&["a", "b", "c"]).unwrap();
+    /// assert_eq!(a, 1);
+    /// assert_eq!(b, "two");
+    /// assert_eq!(c, 3.0);
+    /// ```
+    pub fn get_tuple<T: for<'p> FromPyObject<'p>>(&self, names: &[&str]) -> PyResult<T> {
+        Python::with_gil(|py| {
+            let values = names
+                .iter()
+                .map(|name| self.get_with_gil::<Bound<PyAny>>(py, name))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyTuple::new(py, values)?.extract()
+        })
+    }
+
+    /// Retrieve the result of the Python code, by convention stored in `__result__`.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     numbers = [1, 2, 3]
+    ///     __result__ = sum(numbers)
+    /// };
+    ///
+    /// assert_eq!(c.result::<i32>(), Some(6));
+    /// ```
+    ///
+    /// Returns `None` if `__result__` doesn't exist.
+    /// This function panics if the conversion fails.
+    pub fn result<T: for<'p> FromPyObject<'p>>(&self) -> Option<T> {
+        self.contains("__result__").then(|| self.get("__result__"))
+    }
+
+    fn get_with_gil<'p, T: FromPyObject<'p>>(&self, py: Python<'p>, name: &str) -> PyResult<T> {
+        match self.globals.bind(py).get_item(name)? {
+            Some(value) => value.extract(),
+            None => Err(PyNameError::new_err(format!(
+                "Python context does not contain a variable named `{name}`"
+            ))),
+        }
+    }
+
+    /// Set a global variable in the context.
+    ///
+    /// If the variable already exists, it is replaced.
+    ///
+    /// This function panics if `name` is not a valid Python identifier, or the conversion fails.
+    pub fn set<T: for<'p> IntoPyObject<'p>>(&self, name: &str, value: T) {
+        if let Err(e) = self.try_set(name, value) {
+            panic!(
+                "Unable to set `{name}` from a `{ty}`: {e}",
+                ty = std::any::type_name::<T>(),
+            );
+        }
+    }
+
+    /// Set a global variable in the context, without panicking.
+    ///
+    /// Like [`Context::set`], but returns an error instead of panicking.
+    pub fn try_set<T: for<'p> IntoPyObject<'p>>(&self, name: &str, value: T) -> PyResult<()> {
+        Python::with_gil(|py| {
+            check_identifier(py, name)?;
+            self.globals().bind(py).set_item(name, value)
+        })
+    }
+
+    /// Import a module into the context, like an `import` statement.
+    ///
+    /// `c.import("numpy", Some("np"))` is equivalent to `import numpy as np`,
+    /// and `c.import("os.path", None)` to `import os.path`, which binds `os`.
+    /// The module stays available to all code that is run in this context afterwards.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c = Context::new();
+    /// c.import("collections", Some("col")).unwrap();
+    /// c.import("os.path", None).unwrap();
+    ///
+    /// c.run(python! {
+    ///     assert col.Counter("aab")["a"] == 2
+    ///     assert os.path.join("a", "b") == "a/b"
+    /// });
+    /// ```
+    pub fn import(&self, module: &str, as_name: Option<&str>) -> PyResult<()> {
+        Python::with_gil(|py| {
+            let imported = py.import(module)?;
+            let (name, value) = match as_name {
+                Some(name) => (name, imported),
+                None => {
+                    let package = module.split('.').next().unwrap_or(module);
+                    (package, py.import(package)?)
+                }
+            };
+            check_identifier(py, name)?;
+            self.globals.bind(py).set_item(name, value)
+        })
+    }
+
+    /// Check whether the context contains a global variable with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        Python::with_gil(|py| {
+            self.globals
+                .bind(py)
+                .contains(name)
+                .is_ok_and(|contains| contains)
+        })
+    }
+
+    /// Remove a global variable from the context.
+    ///
+    /// Returns whether the variable existed.
+    ///
+    /// Like [`Context::keys`], this skips the `__dunder__` names that Python defines by itself,
+    /// such as `__builtins__` and `__name__`: those are never removed, and `false` is returned.
+    /// Use [`Context::remove_all`] to remove those as well.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c: Context = python! {
+    ///     foo = 5
+    /// };
+    ///
+    /// assert!(c.remove("foo"));
+    /// assert!(!c.remove("foo"));
+    /// assert!(!c.contains("foo"));
+    /// ```
+    pub fn remove(&self, name: &str) -> bool {
+        !is_dunder(name) && self.remove_all(name)
+    }
+
+    /// Remove a global variable from the context, including `__dunder__` names.
+    ///
+    /// Returns whether the variable existed.
+    pub fn remove_all(&self, name: &str) -> bool {
+        Python::with_gil(|py| {
+            let globals = self.globals.bind(py);
+            globals.contains(name).is_ok_and(|contains| contains) && globals.del_item(name).is_ok()
+        })
+    }
+
+    /// Get the names of all global variables in the context.
+    ///
+    /// This skips the `__dunder__` names that Python defines by itself,
+    /// such as `__builtins__` and `__name__`.
+    /// Use [`Context::keys_all`] to include those.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = self.keys_all();
+        keys.retain(|k| !is_dunder(k));
+        keys
+    }
+
+    /// Get the names of all global variables in the context, including `__dunder__` names.
+    pub fn keys_all(&self) -> Vec<String> {
+        Python::with_gil(|py| {
+            self.globals
+                .bind(py)
+                .keys()
+                .iter()
+                .filter_map(|k| k.extract().ok())
+                .collect()
         })
     }
 
@@ -152,29 +742,300 @@ impl Context {
     /// ```
     ///
     /// This function panics if the Python code fails.
-    pub fn run(
+    pub fn run(&self, code: PythonBlock<impl SetVars>) {
+        Python::with_gil(|py| {
+            self.run_with_gil(py, code);
+        });
+    }
+
+    /// Run Python code using this context, through a unique reference.
+    ///
+    /// This is the same as [`Context::run`], but takes `&mut self`,
+    /// to make it clear in the signature of your own functions that the block changes the globals:
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// fn add_item(c: &mut Context, item: i32) {
+    ///     c.run_mut(python! {
+    ///         items.append('item)
+    ///     });
+    /// }
+    ///
+    /// let mut c: Context = python! {
+    ///     items = []
+    /// };
+    /// add_item(&mut c, 1);
+    /// add_item(&mut c, 2);
+    /// assert_eq!(c.get::<Vec<i32>>("items"), [1, 2]);
+    /// ```
+    ///
+    /// Note that this is only a signal to the Rust borrow checker.
+    /// It does not stop Python from changing the same globals through another reference,
+    /// like the dictionary from [`Context::globals`], another context made with
+    /// [`Context::from_globals`] or [`Context::new_shared`], or a Python thread.
+    ///
+    /// This function panics if the Python code fails.
+    pub fn run_mut(&mut self, code: PythonBlock<impl SetVars>) {
+        self.run(code)
+    }
+
+    /// Run Python code using this context, without panicking.
+    ///
+    /// Like [`Context::run`], but returns the Python exception instead of panicking.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c = Context::new();
+    ///
+    /// let result = c.run_checked(python! {
+    ///     raise ValueError("oops")
+    /// });
+    ///
+    /// assert!(result.is_err());
+    /// ```
+    pub fn run_checked(&self, code: PythonBlock<impl SetVars>) -> PyResult<()> {
+        Python::with_gil(|py| self.try_run_with_gil(py, code).map(drop))
+    }
+
+    /// Run Python code using this context, and get the exception as an [`InlinePythonError`] if it fails.
+    ///
+    /// Like [`Context::run_checked`], but the exception is converted to a type that is easier to inspect,
+    /// with separate fields for the exception type, its arguments, and the traceback.
+    pub fn run_with_exception(
+        &self,
+        code: PythonBlock<impl SetVars>,
+    ) -> Result<(), InlinePythonError> {
+        Ok(self.run_checked(code)?)
+    }
+
+    /// Run Python code using this context, and return whether it succeeded.
+    ///
+    /// Like [`Context::run`], but if the Python code fails, the error is printed
+    /// to stderr (in the same form as the panic message of [`Context::run`])
+    /// and `false` is returned, instead of panicking.
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c = Context::new();
+    ///
+    /// assert!(c.run_ok(python! {
+    ///     x = 1
+    /// }));
+    ///
+    /// assert!(!c.run_ok(python! {
+    ///     x = 1 / 0
+    /// }));
+    /// ```
+    pub fn run_ok(&self, code: PythonBlock<impl SetVars>) -> bool {
+        Python::with_gil(|py| {
+            let source = code.code;
+            match self.try_run_with_gil(py, code) {
+                Ok(_) => true,
+                Err(err) => {
+                    eprintln!("{}", block_error_string(py, &err, source).trim_end());
+                    false
+                }
+            }
+        })
+    }
+
+    /// Run Python code using this context, and get the value of its final expression.
+    ///
+    /// This function should be called using the `python!{}` macro:
+    ///
+    /// ```
+    /// # use inline_python::{Context, python};
+    /// let c = Context::new();
+    ///
+    /// c.run(python! {
+    ///     foo = 5
+    /// });
+    ///
+    /// let x: i32 = c.eval(python! {
+    ///     foo * 2
+    /// }).unwrap();
+    ///
+    /// assert_eq!(x, 10);
+    /// ```
+    ///
+    /// If the Python code does not end in an expression, the value is `None`.
+    ///
+    /// Unlike [`Context::run`], this function does not panic,
+    /// but returns an error if the Python code fails or the conversion fails.
+    pub fn eval<T: for<'p> FromPyObject<'p>>(
+        &self,
+        code: PythonBlock<impl SetVars>,
+    ) -> PyResult<T> {
+        Python::with_gil(|py| self.try_run_with_gil(py, code)?.extract())
+    }
+
+    /// Run Python code from a string using this context.
+    ///
+    /// Unlike with the `python!{}` macro, the code is only compiled at runtime,
+    /// and is not affected by the limitations of the Rust tokenizer.
+    /// There is no `'var` syntax; use [`Context::set`] to give it Rust values.
+    ///
+    /// ```
+    /// # use inline_python::Context;
+    /// let c = Context::new();
+    /// c.set("x", 2);
+    /// c.run_source("y = x * 3").unwrap();
+    /// assert_eq!(c.get::<i32>("y"), 6);
+    /// ```
+    ///
+    /// Returns an error if the code doesn't compile or fails.
+    pub fn run_source(&self, source: &str) -> PyResult<()> {
+        let source = CString::new(source)?;
+        Python::with_gil(|py| py.run(&source, Some(self.globals.bind(py)), None))
+    }
+
+    /// Evaluate a Python expression from a string using this context.
+    ///
+    /// Like [`Context::run_source`], but for a single expression,
+    /// of which the value is converted to `T`.
+    ///
+    /// ```
+    /// # use inline_python::Context;
+    /// let c = Context::new();
+    /// c.set("x", 2);
+    /// assert_eq!(c.eval_source::<i32>("x ** 10").unwrap(), 1024);
+    /// ```
+    pub fn eval_source<T: for<'p> FromPyObject<'p>>(&self, source: &str) -> PyResult<T> {
+        let source = CString::new(source)?;
+        Python::with_gil(|py| {
+            py.eval(&source, Some(self.globals.bind(py)), None)?
+                .extract()
+        })
+    }
+
+    /// Compile Python code from a string, to run it (many times) later.
+    ///
+    /// If the code is a single expression, it is compiled such that
+    /// [`Context::eval_compiled`] gives its value.
+    /// Otherwise, it is compiled as statements, of which the value is `None`.
+    ///
+    /// ```
+    /// # use inline_python::Context;
+    /// let c = Context::new();
+    /// let append = Context::compile("rows.append(f'{name}: {value}')").unwrap();
+    /// c.run_source("rows = []").unwrap();
+    /// for (name, value) in [("a", 1), ("b", 2)] {
+    ///     c.set("name", name);
+    ///     c.set("value", value);
+    ///     c.run_compiled(&append).unwrap();
+    /// }
+    /// assert_eq!(c.get::<Vec<String>>("rows"), ["a: 1", "b: 2"]);
+    /// ```
+    ///
+    /// Returns an error if the code doesn't compile, without running anything.
+    pub fn compile(source: &str) -> PyResult<CompiledSource> {
+        Python::with_gil(|py| {
+            let compile = py.import("builtins")?.getattr("compile")?;
+            // Only a `SyntaxError` means it is not a single expression. Other errors are returned as they are.
+            let code = match compile.call1((source, "<string>", "eval")) {
+                Err(err) if err.is_instance_of::<PySyntaxError>(py) => {
+                    compile.call1((source, "<string>", "exec"))?
+                }
+                result => result?,
+            };
+            Ok(CompiledSource {
+                code: code.unbind(),
+            })
+        })
+    }
+
+    /// Run code compiled by [`Context::compile`] in this context.
+    ///
+    /// Returns an error if the code fails.
+    pub fn run_compiled(&self, code: &CompiledSource) -> PyResult<()> {
+        Python::with_gil(|py| self.eval_compiled_with_gil(py, code).map(drop))
+    }
+
+    /// Run code compiled by [`Context::compile`] in this context, and convert its value to `T`.
+    ///
+    /// Returns an error if the code fails or the conversion fails.
+    pub fn eval_compiled<T: for<'p> FromPyObject<'p>>(&self, code: &CompiledSource) -> PyResult<T> {
+        Python::with_gil(|py| self.eval_compiled_with_gil(py, code)?.extract())
+    }
+
+    fn eval_compiled_with_gil<'p>(
         &self,
-        #[cfg(not(doc))] code: PythonBlock<impl FnOnce(&Bound<PyDict>)>,
-        #[cfg(doc)] code: PythonBlock, // Just show 'PythonBlock' in the docs.
-    ) {
-        Python::with_gil(|py| self.run_with_gil(py, code));
+        py: Python<'p>,
+        code: &CompiledSource,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        unsafe {
+            let result = pyo3::ffi::PyEval_EvalCode(
+                code.code.as_ptr(),
+                self.globals.as_ptr(),
+                std::ptr::null_mut(),
+            );
+            Bound::from_owned_ptr_or_err(py, result)
+        }
     }
 
-    #[cfg(not(doc))]
-    pub(crate) fn run_with_gil<F: FnOnce(&Bound<PyDict>)>(
+    /// Run the block, and return the value of its final expression (or `None`).
+    ///
+    /// Panics if the Python code fails.
+    pub(crate) fn run_with_gil<'p, F: SetVars>(
         &self,
-        py: Python<'_>,
+        py: Python<'p>,
         block: PythonBlock<F>,
-    ) {
-        (block.set_vars)(self.globals().bind(py));
-        if let Err(err) = run_python_code(py, self, block.bytecode) {
-            (block.panic)(panic_string(py, &err));
+    ) -> Bound<'p, PyAny> {
+        let panic = block.panic;
+        let code = block.code;
+        match self.try_run_with_gil(py, block) {
+            Ok(value) => value,
+            Err(err) => panic(block_error_string(py, &err, code)),
         }
     }
+
+    /// Run the block, and return the value of its final expression (or `None`).
+    pub(crate) fn try_run_with_gil<'p, F: SetVars>(
+        &self,
+        py: Python<'p>,
+        block: PythonBlock<F>,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let globals = self.globals().bind(py);
+        let write_back = block.set_vars.set_vars(globals);
+        let value = run_python_code(py, self, block.code.bytecode)?;
+        write_back(globals)?;
+        Ok(value)
+    }
+}
+
+fn is_dunder(name: &str) -> bool {
+    name.len() > 4 && name.starts_with("__") && name.ends_with("__")
+}
+
+/// Check that `name` is a valid Python identifier (and not a keyword).
+fn check_identifier(py: Python, name: &str) -> PyResult<()> {
+    let valid = PyString::new(py, name)
+        .call_method0("isidentifier")?
+        .is_truthy()?
+        && !py
+            .import("keyword")?
+            .call_method1("iskeyword", (name,))?
+            .is_truthy()?;
+    if valid {
+        Ok(())
+    } else {
+        Err(PyValueError::new_err(format!(
+            "`{name}` is not a valid Python identifier"
+        )))
+    }
+}
+
+/// The error message for a failed `python!{}` block, ending with its location in the Rust source.
+fn block_error_string(py: Python, err: &PyErr, code: &Code) -> String {
+    let mut msg = panic_string(py, err);
+    if let Some((line, column)) = code.error_location(py, err) {
+        write!(msg, "  --> {}:{line}:{column}", code.file).unwrap();
+    }
+    msg
 }
 
 fn panic_string(py: Python, err: &PyErr) -> String {
-    match py_err_to_string(py, &err) {
+    match py_err_to_string(py, err) {
         Ok(msg) => msg,
         Err(_) => err.to_string(),
     }
@@ -0,0 +1,98 @@
+use pyo3::{PyErr, PyResult, Python, prelude::*};
+use std::fmt;
+
+/// A Python exception, in a form that is easy to inspect from Rust.
+///
+/// ```
+/// # use inline_python::{Context, python};
+/// let c = Context::new();
+/// let err = c.run_with_exception(python! {
+///     {}["key"]
+/// }).unwrap_err();
+///
+/// assert_eq!(err.type_name, "KeyError");
+/// assert_eq!(err.args, ["'key'"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct InlinePythonError {
+    /// The name of the exception's class, like `"ValueError"`.
+    pub type_name: String,
+    /// The exception converted to a string, like Python would show it after the type name.
+    pub message: String,
+    /// The `repr()` of every element of the exception's `args`.
+    pub args: Vec<String>,
+    /// The frames of the traceback, outermost first.
+    pub traceback: Vec<Frame>,
+}
+
+/// A frame of a Python traceback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// The file name of the code.
+    ///
+    /// For code from `python!{}`, this is the Rust source file
+    /// (and the line numbers match the Rust source), unless set with `//! module: ..`.
+    pub file: String,
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The name of the function, or `<module>` for code outside any function.
+    pub name: String,
+}
+
+impl InlinePythonError {
+    fn from_py_err(py: Python, err: &PyErr) -> PyResult<Self> {
+        let value = err.value(py);
+        let mut traceback = Vec::new();
+        let mut tb = err.traceback(py).map(|tb| tb.into_any());
+        while let Some(t) = tb.filter(|t| !t.is_none()) {
+            let code = t.getattr("tb_frame")?.getattr("f_code")?;
+            traceback.push(Frame {
+                file: code.getattr("co_filename")?.extract()?,
+                line: t.getattr("tb_lineno")?.extract()?,
+                name: code.getattr("co_name")?.extract()?,
+            });
+            tb = Some(t.getattr("tb_next")?);
+        }
+        Ok(Self {
+            type_name: err.get_type(py).name()?.to_string(),
+            message: value.str()?.to_string(),
+            args: value
+                .getattr("args")?
+                .try_iter()?
+                .map(|arg| Ok(arg?.repr()?.to_string()))
+                .collect::<PyResult<_>>()?,
+            traceback,
+        })
+    }
+}
+
+impl From<PyErr> for InlinePythonError {
+    fn from(err: PyErr) -> Self {
+        Python::with_gil(|py| {
+            Self::from_py_err(py, &err).unwrap_or_else(|_| Self {
+                type_name: err
+                    .get_type(py)
+                    .name()
+                    .map_or_else(|_| "<unknown>".to_string(), |name| name.to_string()),
+                message: err
+                    .value(py)
+                    .str()
+                    .map_or_else(|_| String::new(), |message| message.to_string()),
+                args: Vec::new(),
+                traceback: Vec::new(),
+            })
+        })
+    }
+}
+
+impl fmt::Display for InlinePythonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{}", self.type_name)
+        } else {
+            write!(f, "{}: {}", self.type_name, self.message)
+        }
+    }
+}
+
+impl std::error::Error for InlinePythonError {}
@@ -0,0 +1,69 @@
+use crate::{Code, Context, PythonBlock, SetVars};
+use pyo3::{FromPyObject, Py, PyAny, PyResult, Python, call::PyCallArgs, prelude::*};
+
+/// A Python function defined with [`python_fn!{}`](crate::python_fn).
+///
+/// ```
+/// # use inline_python::python_fn;
+/// let add = python_fn! {
+///     def add(a, b):
+///         return a + b
+/// };
+///
+/// let x: i32 = add.call((2, 3)).unwrap();
+/// assert_eq!(x, 5);
+///
+/// let s: String = add.call(("a", "b")).unwrap();
+/// assert_eq!(s, "ab");
+/// ```
+pub struct PythonFn {
+    function: Py<PyAny>,
+}
+
+impl PythonFn {
+    /// Call the function with the given arguments, and convert the return value to `T`.
+    ///
+    /// The arguments are given as a tuple, of which every element is converted using
+    /// [`pyo3::IntoPyObject`]. Use `()` for no arguments, and `(x,)` for a single argument.
+    ///
+    /// Returns an error if the function raises an exception or the conversion fails.
+    pub fn call<T: for<'p> FromPyObject<'p>>(
+        &self,
+        args: impl for<'p> PyCallArgs<'p>,
+    ) -> PyResult<T> {
+        Python::with_gil(|py| self.function.bind(py).call1(args)?.extract())
+    }
+
+    /// Get the Python function object.
+    pub fn function(&self) -> &Py<PyAny> {
+        &self.function
+    }
+}
+
+/// The `python_fn!{}` macro expands to a call to this function.
+#[doc(hidden)]
+#[track_caller]
+pub fn python_fn_macro<F: SetVars>(
+    name: &str,
+    code: &'static Code,
+    set_vars: F,
+    panic: fn(String) -> !,
+) -> PythonFn {
+    Python::with_gil(|py| {
+        let context = Context::new_with_gil(py);
+        context.run_with_gil(
+            py,
+            PythonBlock {
+                code,
+                set_vars,
+                panic,
+            },
+        );
+        match context.globals.bind(py).get_item(name) {
+            Ok(Some(function)) => PythonFn {
+                function: function.unbind(),
+            },
+            _ => panic(format!("Python function `{name}` was not defined")),
+        }
+    })
+}
@@ -0,0 +1,62 @@
+use pyo3::{
+    Bound, IntoPyObject, IntoPyObjectExt, Py, PyAny, PyErr, PyResult, Python, prelude::*,
+    types::PyCFunction,
+};
+use std::sync::Mutex;
+
+/// A Rust iterator, passed to Python as a lazy Python iterator.
+///
+/// The items are only produced (and converted using [`pyo3::IntoPyObject`])
+/// when Python asks for them, rather than collecting all of them up front.
+///
+/// ```
+/// # use inline_python::{PyIter, python};
+/// let squares = PyIter::new((0..).map(|i: u64| i * i));
+/// python! {
+///     for x in 'squares:
+///         if x > 50:
+///             break
+///     assert x == 64
+/// }
+/// ```
+///
+/// Since Python may keep the iterator around for as long as it likes,
+/// the iterator must be `'static` (it can't borrow anything) and `Send`.
+/// To iterate over a collection, move it into the iterator with `into_iter()`.
+pub struct PyIter<I>(I);
+
+impl<I: Iterator> PyIter<I> {
+    /// Wrap an iterator to pass it to Python.
+    pub fn new(iter: impl IntoIterator<IntoIter = I>) -> Self {
+        Self(iter.into_iter())
+    }
+}
+
+impl<'py, I> IntoPyObject<'py> for PyIter<I>
+where
+    I: Iterator + Send + 'static,
+    I::Item: for<'p> IntoPyObject<'p>,
+{
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let builtins = py.import("builtins")?;
+        // `iter(f, sentinel)` makes an iterator that calls `f` until it returns `sentinel`.
+        let sentinel = builtins.getattr("object")?.call0()?;
+        let end = sentinel.clone().unbind();
+        let iter = Mutex::new(self.0);
+        let next =
+            PyCFunction::new_closure(py, None, None, move |args, _| -> PyResult<Py<PyAny>> {
+                let py = args.py();
+                // Don't hold the lock while converting, which might run Python code.
+                let item = iter.lock().unwrap().next();
+                match item {
+                    Some(item) => item.into_py_any(py),
+                    None => Ok(end.clone_ref(py)),
+                }
+            })?;
+        builtins.getattr("iter")?.call1((next, sentinel))
+    }
+}
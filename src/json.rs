@@ -0,0 +1,177 @@
+use pyo3::{
+    Bound, FromPyObject, IntoPyObject, PyAny, PyErr, PyResult, Python,
+    exceptions::{PyTypeError, PyValueError},
+    prelude::*,
+    types::{PyBool, PyDict, PyFloat, PyInt, PyList, PyString, PyTuple},
+};
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+/// A value that is passed to Python by converting it to JSON, using `serde`.
+///
+/// The JSON is turned into native Python objects with `json.loads`:
+/// structs and maps become `dict`s, sequences become `list`s, and so on.
+/// This makes it possible to pass any type that implements [`Serialize`] to Python,
+/// without implementing [`IntoPyObject`] for it.
+///
+/// ```
+/// # use inline_python::{Json, python};
+/// #[derive(serde::Serialize)]
+/// struct Point {
+///     x: i32,
+///     y: i32,
+/// }
+///
+/// let point = Json::new(Point { x: 1, y: 2 });
+/// python! {
+///     assert 'point == {"x": 1, "y": 2}
+/// }
+/// ```
+///
+/// Use [`Context::get_json`](crate::Context::get_json) for the other direction.
+///
+/// This requires the `serde` feature.
+pub struct Json<T>(T);
+
+impl<T: Serialize> Json<T> {
+    /// Wrap a value to pass it to Python as JSON.
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<'py, T: Serialize> IntoPyObject<'py> for Json<T> {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let json = serde_json::to_string(&self.0)
+            .map_err(|e| PyValueError::new_err(format!("Unable to convert to JSON: {e}")))?;
+        py.import("json")?.getattr("loads")?.call1((json,))
+    }
+}
+
+/// A [`serde_json::Value`], converted to and from native Python objects.
+///
+/// Unlike [`Json`], this doesn't go through a JSON string, and works in both directions:
+/// objects become `dict`s, arrays become `list`s, and so on, recursively.
+/// This is useful for data without a fixed schema.
+///
+/// ```
+/// # use inline_python::{Context, JsonValue, python};
+/// let config = JsonValue(serde_json::json!({"name": "test", "retries": [1, 2.5, null]}));
+/// let c: Context = python! {
+///     assert 'config == {"name": "test", "retries": [1, 2.5, None]}
+///     result = {"ok": True, "items": ("a", "b")}
+/// };
+/// let result = c.get::<JsonValue>("result").0;
+/// assert_eq!(result, serde_json::json!({"ok": true, "items": ["a", "b"]}));
+/// ```
+///
+/// Converting back to Rust accepts `None`, `bool`, `int`, `float`, `str`,
+/// `list`, `tuple`, and `dict` with `str` keys.
+/// Anything else gives a `TypeError`, and integers that don't fit in
+/// an `i64` or `u64` or floats that are infinite or NaN give a `ValueError`.
+///
+/// This requires the `serde` feature.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonValue(pub Value);
+
+fn value_to_py<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        Value::Null => py.None().into_bound(py),
+        Value::Bool(b) => PyBool::new(py, *b).to_owned().into_any(),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => n.into_pyobject(py)?.into_any(),
+            (_, Some(n)) => n.into_pyobject(py)?.into_any(),
+            _ => n.as_f64().unwrap_or(f64::NAN).into_pyobject(py)?.into_any(),
+        },
+        Value::String(s) => PyString::new(py, s).into_any(),
+        Value::Array(values) => {
+            let list = PyList::empty(py);
+            for value in values {
+                list.append(value_to_py(py, value)?)?;
+            }
+            list.into_any()
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (key, value) in map {
+                dict.set_item(key, value_to_py(py, value)?)?;
+            }
+            dict.into_any()
+        }
+    })
+}
+
+fn py_to_value(ob: &Bound<PyAny>) -> PyResult<Value> {
+    if ob.is_none() {
+        Ok(Value::Null)
+    } else if let Ok(b) = ob.downcast::<PyBool>() {
+        Ok(Value::Bool(b.is_true()))
+    } else if ob.is_instance_of::<PyInt>() {
+        if let Ok(n) = ob.extract::<i64>() {
+            Ok(Value::from(n))
+        } else if let Ok(n) = ob.extract::<u64>() {
+            Ok(Value::from(n))
+        } else {
+            Err(PyValueError::new_err(format!(
+                "integer too large for JSON: {ob}"
+            )))
+        }
+    } else if let Ok(f) = ob.downcast::<PyFloat>() {
+        Number::from_f64(f.value())
+            .map(Value::Number)
+            .ok_or_else(|| PyValueError::new_err(format!("{ob} can't be represented in JSON")))
+    } else if let Ok(s) = ob.downcast::<PyString>() {
+        Ok(Value::String(s.to_str()?.to_owned()))
+    } else if let Ok(list) = ob.downcast::<PyList>() {
+        list.iter().map(|v| py_to_value(&v)).collect()
+    } else if let Ok(tuple) = ob.downcast::<PyTuple>() {
+        tuple.iter().map(|v| py_to_value(&v)).collect()
+    } else if let Ok(dict) = ob.downcast::<PyDict>() {
+        let mut map = Map::new();
+        for (key, value) in dict {
+            let Ok(key) = key.downcast::<PyString>() else {
+                return Err(PyTypeError::new_err(format!(
+                    "JSON object keys must be strings, not `{}`",
+                    key.get_type().name()?
+                )));
+            };
+            map.insert(key.to_str()?.to_owned(), py_to_value(&value)?);
+        }
+        Ok(Value::Object(map))
+    } else {
+        Err(PyTypeError::new_err(format!(
+            "`{}` can't be converted to JSON",
+            ob.get_type().name()?
+        )))
+    }
+}
+
+impl<'py> IntoPyObject<'py> for JsonValue {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        value_to_py(py, &self.0)
+    }
+}
+
+impl<'py> IntoPyObject<'py> for &JsonValue {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        value_to_py(py, &self.0)
+    }
+}
+
+impl FromPyObject<'_> for JsonValue {
+    fn extract_bound(ob: &Bound<PyAny>) -> PyResult<Self> {
+        py_to_value(ob).map(JsonValue)
+    }
+}
@@ -18,10 +18,104 @@
 //!
 //! Use the `python!{..}` macro to write Python code directly in your Rust code.
 //!
+//! This works on stable Rust (1.88 or newer). The macro uses the line and column
+//! of every token to reconstruct the indentation of the Python code.
+//!
 //! ## Using Rust variables
 //!
 //! To reference Rust variables, use `'var`, as shown in the example above.
 //! `var` needs to implement [`pyo3::IntoPyObject`].
+//! (Within the Python code, the variable is called `_RUST_var`.
+//! Python names starting with `_RUST_` are therefore not allowed in a `python!{}` block.)
+//!
+//! An `Option` becomes either `None` or the value, and converting back
+//! (e.g. with [`Context::get`]) turns `None` into Rust's `None`.
+//! A `Path` or `PathBuf` becomes a `pathlib.Path`, and both `pathlib.Path` and `str`
+//! can be converted back to a `PathBuf`.
+//! To let Python call a Rust function or closure, wrap it in a [`PyCallback`].
+//! A Rust enum becomes a Python `enum.Enum` member when wrapped in a [`PyEnum`].
+//! A `Result` wrapped in a [`PyResultValue`] becomes either its value or an exception object.
+//! With the `serde` feature, a `serde_json::Value` wrapped in a `JsonValue` becomes
+//! nested Python `dict`s, `list`s, and so on, and can be converted back the same way.
+//!
+//! `'var` also works inside the replacement fields of an f-string,
+//! including in a nested format spec:
+//!
+//! ```
+//! # use inline_python::python;
+//! let x = 42;
+//! let width = 6;
+//! python! {
+//!     assert f "x is {'x}" == "x is 42"
+//!     assert f "{'x:>{'width}}" == "    42"
+//! }
+//! ```
+//!
+//! (Inside the braces, a quote that is followed by a name and then another quote
+//! is a Python string, like in `{d['key']}`. Only plain `'var` captures are supported there.)
+//!
+//! `'var` moves the variable into Python (or copies it, if it is `Copy`),
+//! so a `Vec` or `String` can't be used anymore after the block.
+//! To give Python a reference instead, use `'ref var`.
+//! This needs `&T` to implement `IntoPyObject`, which is true for most types (like `Vec`, `String`, and `HashMap`):
+//!
+//! ```
+//! # use inline_python::python;
+//! let v = vec![1, 2, 3];
+//! python! {
+//!     print('ref v)
+//! }
+//! println!("{v:?}");
+//! ```
+//!
+//! A `Vec` or slice becomes a Python `list`, which is allocated once at its final size.
+//! For large amounts of data, nearly all of the time goes to converting every element to a Python object.
+//! Numeric data can be passed without that conversion using an [`AsMemoryView`]
+//! (see `examples/large-vec.rs` for a comparison).
+//! With the `numpy` feature, `NumpyView` does the same for a numpy array with a given shape,
+//! and `Context::get_numpy` copies an array back into a `Vec`.
+//!
+//! Similarly, a `&str`, `String`, or `Cow<str>` is always copied into a new Python `str`,
+//! which also involves decoding the UTF-8 (fast for ASCII, slower otherwise).
+//! A `&[u8]` (e.g. `'_(text.as_bytes())`) becomes `bytes`, which is copied without decoding.
+//! To avoid copying large text entirely, pass `AsMemoryView::new(text.as_bytes())`,
+//! which Python can read (e.g. with `re` or `hashlib`) like `bytes`
+//! (see `examples/large-str.rs`).
+//!
+//! To use the result of an arbitrary Rust expression, use `'_(expr)`:
+//!
+//! ```
+//! # use inline_python::python;
+//! let v = vec![1, 2, 3];
+//! python! {
+//!     assert '_(v.len()) == 3
+//!     assert '_(v.iter().sum::<i32>()) == 6
+//! }
+//! ```
+//!
+//! (`'(expr)` is not possible, since the Rust tokenizer would try to parse it as a character literal.)
+//!
+//! To let the Python code modify a Rust variable, use `'mut var`.
+//! The variable is given to Python by reference, and the (possibly modified)
+//! value is converted back using [`pyo3::FromPyObject`] after the block ran:
+//!
+//! ```
+//! # use inline_python::python;
+//! let mut total = 0;
+//! let mut names = vec!["a".to_string()];
+//! python! {
+//!     for i in range(5):
+//!         'mut total += i
+//!     'mut names.append("b")
+//! }
+//! assert_eq!(total, 10);
+//! assert_eq!(names, ["a", "b"]);
+//! ```
+//!
+//! Since the conversion makes a copy, this works for any type that
+//! implements both `IntoPyObject` (for a reference) and `FromPyObject`.
+//! If the Python code fails, the variable keeps its original value.
+//! If the variable was replaced by something that can't be converted back, the block fails with a `TypeError`.
 //!
 //! ## Re-using a Python context
 //!
@@ -56,6 +150,22 @@
 //! });
 //! ```
 //!
+//! Alternatively, start the code with an `in c;` line to run it in the context `c` directly:
+//!
+//! ```
+//! # use inline_python::{Context, python};
+//! let c = Context::new();
+//!
+//! python! {
+//!   in c;
+//!   foo = 5
+//! }
+//!
+//! c.run(python! {
+//!   assert foo == 5
+//! });
+//! ```
+//!
 //! ## Getting information back
 //!
 //! A [`Context`] object could also be used to pass information back to Rust,
@@ -71,6 +181,129 @@
 //! assert_eq!(c.get::<i32>("foo"), 5);
 //! ```
 //!
+//! If you only need a single value, use [`python_eval!{}`][python_eval] instead,
+//! which evaluates to the value of the final expression in the Python code.
+//!
+//! ```
+//! # use inline_python::python_eval;
+//! let x: i32 = python_eval! {
+//!   foo = 5
+//!   foo * 2
+//! };
+//!
+//! assert_eq!(x, 10);
+//! ```
+//!
+//! ## Large integers
+//!
+//! Python integers have no maximum size. They can be converted to and from `i128` and `u128`,
+//! and, with the `num-bigint` feature enabled, to and from `num_bigint::BigInt` and `BigUint`.
+//! If a Python integer doesn't fit in the requested type, the conversion fails with
+//! an `OverflowError`, which [`Context::try_get`] returns as an error.
+//!
+//! ```
+//! # use inline_python::{Context, python};
+//! let big = i128::MAX;
+//! let c: Context = python! {
+//!   x = 'big + 1
+//! };
+//!
+//! assert_eq!(c.get::<u128>("x"), i128::MAX as u128 + 1);
+//! assert!(c.try_get::<i128>("x").is_err());
+//! ```
+//!
+//! ## Complex numbers
+//!
+//! With the `num-complex` feature enabled, `num_complex::Complex64` and `Complex32`
+//! can be converted to and from Python's `complex`.
+//!
+//! ## Dates and times
+//!
+//! With the `chrono` feature enabled, `chrono`'s date and time types (like `NaiveDate`,
+//! `NaiveDateTime`, and `DateTime<Utc>`) can be converted to and from Python's `datetime` types.
+//!
+//! A `std::time::Duration` is converted to and from a `datetime.timedelta`, also without that feature.
+//! Since a `Duration` can't be negative, extracting a negative `timedelta` fails.
+//! (With the `chrono` feature, `chrono::TimeDelta` can be used for those.)
+//!
+//! ## Compilation mode
+//!
+//! By default, the Python code is compiled like a Python file.
+//! A `//! mode: ..` line at the start of the Python code selects a different mode:
+//!
+//! - `//! mode: single` compiles the code like the interactive interpreter would:
+//!   the value of every expression statement is printed.
+//! - `//! mode: eval` compiles the code as a single expression.
+//!
+//! ```
+//! # use inline_python::python;
+//! python! {
+//!     //! mode: single
+//!     1 + 2 // Prints 3.
+//! }
+//! ```
+//!
+//! ## Module name
+//!
+//! A `//! module: name` line sets `__name__` to the given name before running the code,
+//! and uses that name instead of the Rust file name in Python tracebacks.
+//!
+//! ```
+//! # use inline_python::python_eval;
+//! let name: String = python_eval! {
+//!     //! module: mymod
+//!     __name__
+//! };
+//! assert_eq!(name, "mymod");
+//! ```
+//!
+//! Without it, `__name__` is `"__main__"`.
+//!
+//! ## Random seed
+//!
+//! A `//! seed: 1234` line seeds Python's `random` module before running the code,
+//! which is useful to make the output of `ct_python!{}` reproducible.
+//! (Like [`Context::with_seed`], this affects the `random` module itself, which is shared by all Python code.)
+//!
+//! ## Optimization level
+//!
+//! The code is compiled with `assert` statements enabled, so they can be used to check things.
+//! An `//! optimize: 1` line compiles the code like Python's `-O` flag,
+//! which removes `assert` statements and sets `__debug__` to `False`.
+//! `//! optimize: 2` is like `-OO`, which also removes docstrings.
+//!
+//! ```
+//! # use inline_python::python_eval;
+//! let debug: bool = python_eval! {
+//!     //! optimize: 1
+//!     assert False
+//!     __debug__
+//! };
+//! assert!(!debug);
+//! ```
+//!
+//! ## Validating without running
+//!
+//! Syntax errors in a `python!{}` block are already reported while compiling the Rust code,
+//! since the macro compiles the Python code to bytecode.
+//! When building with `RUSTFLAGS="--cfg inline_python_validate_only"`,
+//! the blocks are still compiled, but no longer run:
+//! the captured Rust variables are converted as usual, but the bytecode is never executed.
+//! This is meant for builds that only check the code, like a linting job in CI.
+//!
+//! This is not a Cargo feature, because Cargo enables a feature for everyone that uses a crate
+//! as soon as one crate in the dependency graph enables it, which would silently turn
+//! the `python!{}` blocks of every other crate into no-ops.
+//! A `--cfg` flag can only be set by whoever runs the build, and applies to that whole build.
+//!
+//! Since nothing runs, nothing can come out of the code either:
+//! a [`Context`] doesn't get any new variables, [`python_eval!`] results in `None`
+//! (so it panics unless the result type accepts `None`),
+//! and [`python_fn!`] panics because the function is never defined.
+//! Don't use it for tests or programs that are actually used.
+//!
+//! Python and pyo3 are still linked, since the captured variables and [`Context`]s still exist.
+//!
 //! ## Syntax issues
 //!
 //! Since the Rust tokenizer will tokenize the Python code, some valid Python
@@ -79,12 +312,28 @@
 //! - Use double quoted strings (`""`) instead of single quoted strings (`''`).
 //!
 //!   (Single quoted strings only work if they contain a single character, since
-//!   in Rust, `'a'` is a character literal.)
-//!
-//! - Use `//`-comments instead of `#`-comments.
-//!
-//!   (If you use `#` comments, the Rust tokenizer will try to tokenize your
-//!   comment, and complain if your comment doesn't tokenize properly.)
+//!   in Rust, `'a'` is a character literal. Longer single quoted strings are
+//!   rejected by the Rust tokenizer before the macro gets to see them.
+//!   Note that `'a'` is always a string, while `'a` (without the closing quote)
+//!   refers to the Rust variable `a`.)
+//!
+//! - `#`-comments only work if their content can be tokenized by Rust.
+//!   Use `//`-comments if that's a problem.
+//!
+//!   (The Rust tokenizer will try to tokenize your comment, and complain if
+//!   your comment doesn't tokenize properly, for example due to unbalanced
+//!   brackets or quotes.
+//!   A bracket or string that is opened in a comment and only closed on a later line
+//!   is rejected too, since it would take the code on the lines in between into the comment.)
+//!
+//!   ```compile_fail
+//!   # use inline_python::python;
+//!   python! {
+//!       x = 1 # note (a
+//!       y = 2
+//!       # b)
+//!   }
+//!   ```
 //!
 //! - Write `f ""` instead of `f""`.
 //!
@@ -96,35 +345,129 @@
 //!
 //! - The `//` and `//=` operators are unusable, as they start a comment.
 //!
-//!   Workaround: you can write `##` instead, which is automatically converted
-//!   to `//`.
+//!   Workaround: you can write `#/` and `#/=` instead, which are automatically
+//!   converted to `//` and `//=`.
+//!   (Before Rust 2024, `##` and `##=` also work, but the Rust 2024 tokenizer
+//!   rejects `##`.)
+//!
+//!   ```edition2021
+//!   # use inline_python::python;
+//!   python! {
+//!       x = 7 ## 2
+//!       x ##= 2
+//!       x #/= 1
+//!       assert x == 1
+//!   }
+//!   ```
 //!
 //! - Certain escape codes in string literals.
 //!   (Specifically: `\a`, `\b`, `\f`, `\v`, `\N{..}`, `\123` (octal escape
-//!   codes), `\u`, and `\U`.)
+//!   codes), `\u`, `\U`, and `\x80` through `\xFF`.)
+//!
+//!   These, however, are accepted just fine: `\\`, `\n`, `\t`, `\r`, `\x00`
+//!   through `\x7F` (hex escape codes), and `\0`.
 //!
-//!   These, however, are accepted just fine: `\\`, `\n`, `\t`, `\r`, `\xAB`
-//!   (hex escape codes), and `\0`.
+//!   For unicode escapes, use Rust's syntax (`\u{E9}`), which is automatically
+//!   converted to Python's syntax (`\U000000e9`).
 //!
 //! - Raw string literals with escaped double quotes. (E.g. `r"...\"..."`.)
 //!
-//! - Triple-quoted byte- and raw-strings with content that would not be valid
-//!   as a regular string. And the same for raw-byte and raw-format strings.
-//!   (E.g. `b"""\xFF"""`, `r"""\z"""`, `fr"\z"`, `br"\xFF"`.)
+//!   Use Rust's syntax instead (`r#"..."\"..."#`), which is converted to a Python
+//!   raw string with the same content. Rust raw strings can also span multiple
+//!   lines, making them an alternative to triple-quoted raw strings.
+//!
+//! - Triple-quoted byte-strings with content that would not be valid
+//!   as a regular string. (E.g. `b"""\xFF"""`.)
 //!
 //! Everything else should work fine.
+//!
+//! When a `SyntaxError` looks like it is caused by one of these (like a `//` that
+//! was taken as a Rust comment), the error message includes a hint.
+//! That includes a `//` right after a token, without a space in between:
+//!
+//! ```compile_fail
+//! # use inline_python::python;
+//! let x = 4;
+//! python! {
+//!     if 'x// 2 == 2:
+//!         print("two")
+//! }
+//! ```
+//!
+//! ## Warnings
+//!
+//! Warnings produced while compiling the Python code (like `SyntaxWarning`)
+//! are reported as Rust compiler warnings, pointing at the relevant line.
+//! (Since proc macros can't emit warnings directly, these show up as the use of a deprecated `PythonWarning` item.)
+//! Warnings produced while running the code are printed to stderr by Python, as usual.
+//!
+//! ## Debugging
+//!
+//! To see the Python code exactly as it was reconstructed from the Rust tokens,
+//! set the `INLINE_PYTHON_DUMP=1` environment variable while compiling.
+//! The code of every `python!{}` and `ct_python!{}` block is then printed during compilation.
+//! (Cargo does not know about this variable, so you might need to force a
+//! recompilation, e.g. by touching the source file.)
+//!
+//! To find out which blocks take up most of the build time, set `INLINE_PYTHON_PROFILE=1`.
+//! For every block, a note with the time it took to compile the Python code is printed,
+//! together with its location.
+//! For `ct_python!{}`, the note also includes the time it took to run the code.
+//! (Blocks that `ct_python!{}` reuses from its [cache](https://docs.rs/ct-python/#caching) are not run again, and not reported.)
+//!
+//! When a block fails at runtime, the panic message contains the Python traceback,
+//! followed by the Rust source location (`file:line:column`) of the innermost line within the block
+//! that was involved. This works even when the block was created in one place and run in another,
+//! or when `//! module: ..` changed the file name that Python shows in the traceback.
 
-use pyo3::{Bound, Python, types::PyDict};
+use pyo3::{
+    Bound, FromPyObject, Python,
+    exceptions::{PyNameError, PyTypeError},
+    prelude::*,
+    types::PyDict,
+};
 
+mod callback;
+mod compiled;
 mod context;
+mod error;
+mod function;
+mod iter;
+#[cfg(feature = "serde")]
+mod json;
+mod memoryview;
+mod namedtuple;
+#[cfg(feature = "numpy")]
+mod numpy;
+mod ordered;
+mod pyenum;
+mod result;
 mod run;
+mod tuple;
 
+pub use self::callback::{Callback, PyCallback};
+pub use self::compiled::CompiledSource;
 pub use self::context::Context;
+pub use self::error::{Frame, InlinePythonError};
+pub use self::function::{PythonFn, python_fn_macro};
+pub use self::iter::PyIter;
+#[cfg(feature = "serde")]
+pub use self::json::{Json, JsonValue};
+pub use self::memoryview::{AsMemoryView, MemoryViewElement};
+pub use self::namedtuple::NamedTuple;
+#[cfg(feature = "numpy")]
+pub use self::numpy::NumpyView;
+pub use self::ordered::Ordered;
+pub use self::pyenum::{IntoPyEnum, PyEnum};
+pub use self::result::PyResultValue;
+#[doc(hidden)]
+pub use self::run::Code;
+pub use self::tuple::PyTupleOf;
 pub use pyo3;
 
 /// A block of Python code within your Rust code.
 ///
-/// This macro can be used in three different ways:
+/// This macro can be used in four different ways:
 ///
 ///  1. By itself as a statement.
 ///     In this case, the Python code is executed directly.
@@ -134,61 +477,398 @@ pub use pyo3;
 ///     (the global variables) are available for re-use by other Python code
 ///     or inspection by Rust code.
 ///
-///  3. By passing it as an argument to a function taking a `PythonBlock`, such
+///  3. By passing it as an argument to a function taking a [`PythonBlock`], such
 ///     as [`Context::run`].
 ///
+///  4. By starting the code with an `in c;` line, which runs it in the [`Context`] `c`,
+///     like `c.run(python!{..})`.
+///
 /// See [the crate's module level documentation](index.html) for examples.
 pub use inline_python_macros::python;
 
-// `python!{..}` expands to `python_impl!{b"bytecode" var1 var2 …}`,
+/// A block of Python code that evaluates to a Rust value.
+///
+/// The Python code must end in an expression.
+/// The value of that expression is converted to the Rust type using [`pyo3::FromPyObject`].
+///
+/// ```
+/// # use inline_python::python_eval;
+/// let n = 5;
+/// let x: i32 = python_eval! {
+///     squares = [i * i for i in range('n)]
+///     sum(squares)
+/// };
+/// assert_eq!(x, 30);
+/// ```
+///
+/// The code runs in a new context, just like a `python!{}` block used as a statement,
+/// unless it starts with an `in c;` line, which runs it in the context `c`:
+///
+/// ```
+/// # use inline_python::{Context, python_eval};
+/// let c = Context::new();
+/// c.set("base", 10);
+/// let x: i32 = python_eval! {
+///     in c;
+///     base * 2
+/// };
+/// assert_eq!(x, 20);
+/// ```
+pub use inline_python_macros::python_eval;
+
+/// A Python function that can be called from Rust.
+///
+/// The Python code must consist of a single `def` statement.
+/// It is run once, when the `python_fn!{}` expression is evaluated,
+/// in a new context. The result is a [`PythonFn`] that can be called repeatedly.
+///
+/// ```
+/// # use inline_python::python_fn;
+/// let factor = 3;
+/// let scale = python_fn! {
+///     def scale(values):
+///         return [v * 'factor for v in values]
+/// };
+///
+/// for i in 0..3 {
+///     let v: Vec<i32> = scale.call((vec![i, 10],)).unwrap();
+///     assert_eq!(v, [i * 3, 30]);
+/// }
+/// ```
+///
+/// Captured Rust variables (like `'factor` above) are evaluated when the function is defined,
+/// not every time it is called.
+pub use inline_python_macros::python_fn;
+
+/// Make a Python tuple out of any number of values.
+///
+/// PyO3 only converts Rust tuples of up to twelve elements.
+/// For a longer tuple, use `py_tuple!(a, b, c, ..)`.
+/// Every value needs to implement [`pyo3::IntoPyObject`].
+/// The values are evaluated right away, and moved into the resulting [`PyTupleOf`].
+///
+/// ```
+/// # use inline_python::{py_tuple, python};
+/// let t = py_tuple!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, "thirteen");
+/// python! {
+///     assert len('t) == 13
+///     assert 't[-1] == "thirteen"
+/// }
+/// ```
+///
+/// In the other direction, a tuple that is too long for a Rust tuple can be
+/// extracted as a `Vec`, with elements of one type, or of [`PyObject`](pyo3::PyObject)
+/// to extract every element separately:
+///
+/// ```
+/// # use inline_python::{Context, python};
+/// use inline_python::pyo3::{PyObject, Python};
+///
+/// let c: Context = python! {
+///     t = tuple(range(12)) + ("twelve",)
+/// };
+/// let t: Vec<PyObject> = c.get("t");
+/// Python::with_gil(|py| {
+///     assert_eq!(t[0].extract::<i32>(py).unwrap(), 0);
+///     assert_eq!(t[12].extract::<String>(py).unwrap(), "twelve");
+/// });
+/// ```
+#[macro_export]
+macro_rules! py_tuple {
+    ($($value:expr),* $(,)?) => {
+        $crate::py_tuple!(@ [] $($value,)*)
+    };
+    // Evaluate the values one by one, in order, binding every one to a new (hygienic) `value`.
+    (@ [$($done:ident)*] $value:expr, $($rest:expr,)*) => {
+        match $value {
+            value => $crate::py_tuple!(@ [$($done)* value] $($rest,)*),
+        }
+    };
+    (@ []) => {
+        $crate::PyTupleOf::new(|py| Ok($crate::pyo3::types::PyTuple::empty(py)))
+    };
+    (@ [$($done:ident)+]) => {
+        $crate::PyTupleOf::new(move |py| {
+            $crate::pyo3::types::PyTuple::new(
+                py,
+                [$($crate::pyo3::IntoPyObjectExt::into_bound_py_any($done, py)?),*],
+            )
+        })
+    };
+}
+
+/// Write the body of a Rust function in Python.
+///
+/// The parameters of the function are available to the Python code under the same names,
+/// converted with [`pyo3::IntoPyObject`].
+/// The value returned by the Python code (with `return`) is converted to the return type
+/// of the function with [`pyo3::FromPyObject`]:
+///
+/// ```
+/// use inline_python::python_body;
+///
+/// #[python_body]
+/// fn add(a: i32, b: i32) -> i32 {
+///     return a + b
+/// }
+///
+/// #[python_body]
+/// fn describe(name: &str, values: Vec<f64>) -> String {
+///     python! {
+///         total = sum(values)
+///         return f "{name}: {total:.1f}"
+///     }
+/// }
+///
+/// assert_eq!(add(2, 3), 5);
+/// assert_eq!(describe("sum", vec![1.0, 2.5]), "sum: 3.5");
+/// ```
+///
+/// The Rust compiler checks that the function is valid Rust syntax before the attribute is applied,
+/// and `rustfmt` formats it as Rust code.
+/// So, a body directly in the braces of the function only works for very simple code, like `add` above.
+/// Anything else needs to be wrapped in `python! {..}`, as in `describe`.
+/// (That `python!` is part of the syntax of `#[python_body]`, and is not expanded as a separate block.)
+///
+/// Every call runs the Python code in a new [`Context`], like [`python_eval!{}`].
+/// Within the body, `'var` can be used to capture other Rust variables
+/// that are in scope, like in a `python!{}` block.
+/// Without a return type, the return value of the Python code is ignored.
+///
+/// Only parameters of the form `name: Type` (or `mut name: Type`) are supported.
+/// Like [`python!{}`], the function panics if the Python code raises an exception,
+/// or if the return value can't be converted.
+pub use inline_python_macros::python_body;
+
+/// Run several Python blocks in order, in one new context.
+///
+/// Every `{ .. }` block is a separate `python!{}` block, optionally separated by `;`.
+/// They share their globals, like blocks run in the same [`Context`].
+/// If a block fails, the resulting panic stops the remaining blocks from running.
+///
+/// The result is the [`Context`], unless the last block is written as `eval { .. }`,
+/// in which case it is used like [`python_eval!{}`], and the result is its value:
+///
+/// ```
+/// # use inline_python::python_scope;
+/// let n = 4;
+/// let total: i32 = python_scope! {
+///     {
+///         values = list(range('n))
+///     }
+///     {
+///         squares = [v * v for v in values]
+///     }
+///     eval {
+///         sum(squares)
+///     }
+/// };
+/// assert_eq!(total, 14);
+/// ```
+///
+/// This is the same as creating a [`Context`] and using [`Context::run`] for every block,
+/// without having to name the context.
+#[macro_export]
+macro_rules! python_scope {
+    (@run $context:ident) => {
+        $context
+    };
+    (@run $context:ident ; $($rest:tt)*) => {
+        $crate::python_scope!(@run $context $($rest)*)
+    };
+    (@run $context:ident eval { $($code:tt)* } $(;)?) => {
+        $crate::python_eval! { in $context; $($code)* }
+    };
+    (@run $context:ident { $($code:tt)* } $($rest:tt)*) => {{
+        $crate::python! { in $context; $($code)* }
+        $crate::python_scope!(@run $context $($rest)*)
+    }};
+    ($($blocks:tt)*) => {{
+        let context = $crate::Context::new();
+        $crate::python_scope!(@run context $($blocks)*)
+    }};
+}
+
+// `python!{..}` expands to `_python_block!{b"bytecode" [(line, column), …] &mut _RUST_var1 = var1; _RUST_var2 = var2; _RUST_00000 = (expr); …}`,
 // which then expands to a call to `FromInlinePython::from_python_macro`.
+// The `[(line, column), …]` list has the first Rust token of every line, to point runtime errors at the Rust source.
+// `python_eval!{..}` expands to `_python_block!{eval b"bytecode" …}`,
+// which expands to a call to `eval_python_macro` instead.
+// With an `in c;` line, these expand to `_python_block!{in (c) b"bytecode" …}`
+// and `_python_block!{eval in (c) b"bytecode" …}`, which use the given context.
 #[macro_export]
 #[doc(hidden)]
 macro_rules! _python_block {
-    ($bytecode:literal $($var:ident)*) => {
+    // The closure that puts all the captured variables in the 'globals' dictionary.
+    // It returns the closure that writes the `'mut` variables back afterwards.
+    (@vars $(&mut $mut_name:ident = $mut_var:ident;)* $($name:ident = $value:expr;)*) => {{
+        $(let $mut_name = &mut $mut_var;)*
+        |globals: &$crate::pyo3::Bound<$crate::pyo3::types::PyDict>| {
+            $(
+                $crate::pyo3::prelude::PyDictMethods::set_item(
+                    globals, stringify!($mut_name), &*$mut_name
+                ).expect("python");
+            )*
+            $(
+                $crate::pyo3::prelude::PyDictMethods::set_item(
+                    globals, stringify!($name), $value
+                ).expect("python");
+            )*
+            move |globals: &$crate::pyo3::Bound<$crate::pyo3::types::PyDict>| {
+                $(*$mut_name = $crate::write_back_var(globals, stringify!($mut_name))?;)*
+                $crate::pyo3::PyResult::Ok(())
+            }
+        }
+    }};
+    // The `&'static Code` with the bytecode and the location of every line.
+    (@code $bytecode:literal $lines:tt) => {{
+        const CODE: $crate::Code = $crate::Code {
+            bytecode: $bytecode,
+            file: ::core::file!(),
+            lines: &$lines,
+        };
+        &CODE
+    }};
+    (in $context:tt $bytecode:literal $lines:tt $($vars:tt)*) => {
+        $crate::Context::run(&$context, $crate::_python_block!($bytecode $lines $($vars)*))
+    };
+    (eval in $context:tt $bytecode:literal $lines:tt $($vars:tt)*) => {
+        $crate::eval_python_macro(
+            ::std::option::Option::Some(&$context),
+            $crate::_python_block!(@code $bytecode $lines),
+            $crate::_python_block!(@vars $($vars)*),
+            |e| ::std::panic::panic_any(e),
+        )
+    };
+    (fn $name:literal $bytecode:literal $lines:tt $($vars:tt)*) => {
+        $crate::python_fn_macro(
+            $name,
+            $crate::_python_block!(@code $bytecode $lines),
+            $crate::_python_block!(@vars $($vars)*),
+            |e| ::std::panic::panic_any(e),
+        )
+    };
+    (eval $bytecode:literal $lines:tt $($vars:tt)*) => {
+        $crate::eval_python_macro(
+            ::std::option::Option::None,
+            $crate::_python_block!(@code $bytecode $lines),
+            $crate::_python_block!(@vars $($vars)*),
+            |e| ::std::panic::panic_any(e),
+        )
+    };
+    ($bytecode:literal $lines:tt $($vars:tt)*) => {
         $crate::FromInlinePython::from_python_macro(
-            // The compiled python bytecode:
-            $bytecode,
+            // The compiled python bytecode, and where every line came from:
+            $crate::_python_block!(@code $bytecode $lines),
             // The closure that puts all the captured variables in the 'globals' dictionary:
-            |globals| {
-                $(
-                    $crate::pyo3::prelude::PyDictMethods::set_item(
-                        globals, concat!("_RUST_", stringify!($var)), $var
-                    ).expect("python");
-                )*
-            },
+            $crate::_python_block!(@vars $($vars)*),
             // The closure that is used to throw panics with the right location:
             |e| ::std::panic::panic_any(e),
         )
     }
 }
 
+/// The closure generated by `python!{}` that puts the captured variables in the globals.
+///
+/// This is the type parameter of [`PythonBlock`].
+pub trait SetVars {
+    /// Set the variables, and return a function that writes the `'mut` variables back.
+    #[doc(hidden)]
+    fn set_vars(self, globals: &Bound<PyDict>) -> impl FnOnce(&Bound<PyDict>) -> PyResult<()>;
+}
+
+impl<F, W> SetVars for F
+where
+    F: FnOnce(&Bound<PyDict>) -> W,
+    W: FnOnce(&Bound<PyDict>) -> PyResult<()>,
+{
+    fn set_vars(self, globals: &Bound<PyDict>) -> impl FnOnce(&Bound<PyDict>) -> PyResult<()> {
+        self(globals)
+    }
+}
+
+/// Get the new value of a `'mut` variable after running the block.
 #[doc(hidden)]
-pub trait FromInlinePython<F: FnOnce(&Bound<PyDict>)> {
+pub fn write_back_var<T: for<'p> FromPyObject<'p>>(
+    globals: &Bound<PyDict>,
+    name: &str,
+) -> PyResult<T> {
+    let var = name.strip_prefix("_RUST_").unwrap_or(name);
+    let Some(value) = globals.get_item(name)? else {
+        return Err(PyNameError::new_err(format!(
+            "`'mut {var}` was deleted by the Python code"
+        )));
+    };
+    value.extract().map_err(|e| {
+        PyTypeError::new_err(format!(
+            "Unable to convert `'mut {var}` back to `{ty}`: {e}",
+            ty = std::any::type_name::<T>(),
+        ))
+    })
+}
+
+#[doc(hidden)]
+pub trait FromInlinePython<F: SetVars> {
     /// The `python!{}` macro expands to a call to this function.
-    fn from_python_macro(bytecode: &'static [u8], set_vars: F, panic: fn(String) -> !) -> Self;
+    fn from_python_macro(code: &'static Code, set_vars: F, panic: fn(String) -> !) -> Self;
+}
+
+/// The `python_eval!{}` macro expands to a call to this function.
+#[doc(hidden)]
+#[track_caller]
+pub fn eval_python_macro<T: for<'p> FromPyObject<'p>, F: SetVars>(
+    context: Option<&Context>,
+    code: &'static Code,
+    set_vars: F,
+    panic: fn(String) -> !,
+) -> T {
+    Python::with_gil(|py| {
+        let new_context;
+        let context = match context {
+            Some(context) => context,
+            None => {
+                new_context = Context::new_with_gil(py);
+                &new_context
+            }
+        };
+        let value = context.run_with_gil(
+            py,
+            PythonBlock {
+                code,
+                set_vars,
+                panic,
+            },
+        );
+        match value.extract() {
+            Ok(value) => value,
+            Err(e) => panic(format!(
+                "Unable to convert the result to `{ty}`: {e}",
+                ty = std::any::type_name::<T>(),
+            )),
+        }
+    })
 }
 
 /// Converting a `python!{}` block to `()` will run the Python code.
 ///
 /// This happens when `python!{}` is used as a statement by itself.
-impl<F: FnOnce(&Bound<PyDict>)> FromInlinePython<F> for () {
+impl<F: SetVars> FromInlinePython<F> for () {
     #[track_caller]
-    fn from_python_macro(bytecode: &'static [u8], set_vars: F, panic: fn(String) -> !) {
-        let _: Context = FromInlinePython::from_python_macro(bytecode, set_vars, panic);
+    fn from_python_macro(code: &'static Code, set_vars: F, panic: fn(String) -> !) {
+        let _: Context = FromInlinePython::from_python_macro(code, set_vars, panic);
     }
 }
 
 /// Assigning a `python!{}` block to a `Context` will run the Python code and capture the resulting context.
-impl<F: FnOnce(&Bound<PyDict>)> FromInlinePython<F> for Context {
+impl<F: SetVars> FromInlinePython<F> for Context {
     #[track_caller]
-    fn from_python_macro(bytecode: &'static [u8], set_vars: F, panic: fn(String) -> !) -> Self {
+    fn from_python_macro(code: &'static Code, set_vars: F, panic: fn(String) -> !) -> Self {
         Python::with_gil(|py| {
             let context = Context::new_with_gil(py);
             context.run_with_gil(
                 py,
                 PythonBlock {
-                    bytecode,
+                    code,
                     set_vars,
                     panic,
                 },
@@ -199,27 +879,52 @@ impl<F: FnOnce(&Bound<PyDict>)> FromInlinePython<F> for Context {
 }
 
 /// Using a `python!{}` block as a `PythonBlock` object will not do anything yet.
-#[cfg(not(doc))]
-impl<F: FnOnce(&Bound<PyDict>)> FromInlinePython<F> for PythonBlock<F> {
-    fn from_python_macro(bytecode: &'static [u8], set_vars: F, panic: fn(String) -> !) -> Self {
+impl<F: SetVars> FromInlinePython<F> for PythonBlock<F> {
+    fn from_python_macro(code: &'static Code, set_vars: F, panic: fn(String) -> !) -> Self {
         Self {
-            bytecode,
+            code,
             set_vars,
             panic,
         }
     }
 }
 
-/// Represents a `python!{}` block.
-#[cfg(not(doc))]
+/// A `python!{}` block that has not been run yet.
+///
+/// This is what `python!{}` evaluates to when it is passed to a function taking a `PythonBlock`,
+/// such as [`Context::run`]. The type parameter is the (unnameable) type of the
+/// closure that sets the captured Rust variables, so functions that take a block
+/// are written with `impl SetVars`:
+///
+/// ```
+/// # use inline_python::{Context, PythonBlock, SetVars, python};
+/// fn run_twice(c: &Context, block: PythonBlock<impl SetVars + Clone>) {
+///     block.clone().run(c);
+///     block.run(c);
+/// }
+///
+/// let c = Context::new();
+/// c.set("n", 0);
+/// run_twice(&c, python! {
+///     n += 1
+/// });
+/// assert_eq!(c.get::<i32>("n"), 2);
+/// ```
+#[derive(Clone)]
 pub struct PythonBlock<F> {
-    bytecode: &'static [u8],
+    code: &'static Code,
     set_vars: F,
     panic: fn(String) -> !,
 }
 
-/// In the documentation, we just show `PythonBlock` in
-/// `Context::run`'s signature, without any generic arguments.
-#[cfg(doc)]
-#[doc(hidden)]
-pub struct PythonBlock;
+impl<F: SetVars> PythonBlock<F> {
+    /// Run the code in the given context.
+    ///
+    /// This is the same as [`Context::run`].
+    ///
+    /// This function panics if the Python code fails.
+    #[track_caller]
+    pub fn run(self, context: &Context) {
+        context.run(self);
+    }
+}
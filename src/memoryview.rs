@@ -0,0 +1,123 @@
+use pyo3::{Bound, IntoPyObject, PyAny, PyErr, PyResult, Python, ffi};
+use std::ffi::{CStr, c_void};
+
+/// A zero-copy view of a Rust slice, passed to Python as a read-only `memoryview`.
+///
+/// ```
+/// # use inline_python::{AsMemoryView, python};
+/// let data = vec![1.0f64, 2.0, 3.0];
+/// let view = unsafe { AsMemoryView::new(&data) };
+/// python! {
+///     assert 'view.format == "d"
+///     assert 'view.tolist() == [1.0, 2.0, 3.0]
+///     assert sum('view) == 6.0
+/// }
+/// ```
+///
+/// The memoryview points directly into the Rust slice.
+/// Python can read it, but not write to it.
+///
+/// Other objects that support the buffer protocol, such as numpy arrays, can be
+/// constructed from it without copying the data (e.g. with `numpy.frombuffer`).
+///
+/// A view of a string's bytes (`AsMemoryView::new(text.as_bytes())`) can be used
+/// in most places that accept `bytes`, to avoid copying large text:
+///
+/// ```
+/// # use inline_python::{AsMemoryView, python};
+/// let text = "line 1\nline 2\n".repeat(1000);
+/// let view = unsafe { AsMemoryView::new(text.as_bytes()) };
+/// python! {
+///     import re
+///     assert len(re.findall(b"\n", 'view)) == 2000
+///     assert 'view[:6] == b"line 1"
+/// }
+/// ```
+pub struct AsMemoryView<'a, T: MemoryViewElement>(&'a [T]);
+
+impl<'a, T: MemoryViewElement> AsMemoryView<'a, T> {
+    /// Wrap a slice to pass it to Python as a `memoryview`.
+    ///
+    /// # Safety
+    ///
+    /// Python has no way of knowing the lifetime of the slice.
+    /// The memoryview, and anything that borrows its buffer (like a numpy
+    /// array created with `numpy.frombuffer`), must not be used after the
+    /// slice is no longer borrowed.
+    ///
+    /// In practice, this means the Python code may only use it within the
+    /// block it is passed to, and must not keep a reference to it in a global
+    /// variable of a [`Context`](crate::Context) that outlives the slice.
+    pub unsafe fn new(slice: &'a [T]) -> Self {
+        Self(slice)
+    }
+}
+
+impl<'py, T: MemoryViewElement> IntoPyObject<'py> for AsMemoryView<'_, T> {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        unsafe {
+            let mut buffer = std::mem::zeroed::<ffi::Py_buffer>();
+            // With no flags, this fills in a one-dimensional, read-only
+            // buffer of bytes, without shape or strides.
+            if ffi::PyBuffer_FillInfo(
+                &mut buffer,
+                std::ptr::null_mut(),
+                self.0.as_ptr() as *mut c_void,
+                size_of_val(self.0) as ffi::Py_ssize_t,
+                1,
+                0,
+            ) != 0
+            {
+                return Err(PyErr::fetch(py));
+            }
+            // The memoryview derives the shape and strides from the item size.
+            // The format string is not copied, which is why it must be 'static.
+            buffer.format = T::FORMAT.as_ptr() as *mut _;
+            buffer.itemsize = size_of::<T>() as ffi::Py_ssize_t;
+            Bound::from_owned_ptr_or_err(py, ffi::PyMemoryView_FromBuffer(&buffer))
+        }
+    }
+}
+
+/// A type that can be the element type of an [`AsMemoryView`].
+///
+/// This is implemented for the primitive integer and floating point types.
+pub trait MemoryViewElement: sealed::Sealed + Copy {
+    /// The `struct` module format character for this type.
+    #[doc(hidden)]
+    const FORMAT: &'static CStr;
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+macro_rules! impl_element {
+    ($($t:ty => $format:literal,)*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl MemoryViewElement for $t {
+                const FORMAT: &'static CStr = $format;
+            }
+        )*
+    };
+}
+
+impl_element! {
+    u8 => c"B",
+    i8 => c"b",
+    u16 => c"H",
+    i16 => c"h",
+    u32 => c"I",
+    i32 => c"i",
+    u64 => c"Q",
+    i64 => c"q",
+    usize => c"N",
+    isize => c"n",
+    f32 => c"f",
+    f64 => c"d",
+}
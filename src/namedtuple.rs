@@ -0,0 +1,54 @@
+use pyo3::{Bound, IntoPyObject, IntoPyObjectExt, PyAny, PyErr, PyResult, Python, prelude::*};
+
+/// A tuple, passed to Python as a `collections.namedtuple` with the given field names.
+///
+/// ```
+/// # use inline_python::{NamedTuple, python};
+/// let point = NamedTuple::new("Point", &["x", "y"], (1, 2.5));
+/// python! {
+///     assert 'point.x == 1
+///     assert 'point.y == 2.5
+///     assert 'point == (1, 2.5)
+///     assert repr('point) == "Point(x=1, y=2.5)"
+/// }
+/// ```
+///
+/// The values can be a tuple, or anything else that converts to a Python iterable, like a `Vec`.
+/// If the number of fields doesn't match the number of values, the conversion fails with a `TypeError`.
+///
+/// A new namedtuple class is created for every conversion,
+/// so two values with the same name and fields will not be of the same Python class.
+/// They still compare equal if their values do, since they are tuples.
+pub struct NamedTuple<'a, T> {
+    name: &'a str,
+    fields: &'a [&'a str],
+    values: T,
+}
+
+impl<'a, T> NamedTuple<'a, T> {
+    /// Wrap a tuple to pass it to Python as a namedtuple called `name`.
+    pub fn new(name: &'a str, fields: &'a [&'a str], values: T) -> Self {
+        Self {
+            name,
+            fields,
+            values,
+        }
+    }
+}
+
+impl<'py, T> IntoPyObject<'py> for NamedTuple<'_, T>
+where
+    T: IntoPyObject<'py>,
+{
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let class = py
+            .import("collections")?
+            .getattr("namedtuple")?
+            .call1((self.name, self.fields))?;
+        class.call_method1("_make", (self.values.into_bound_py_any(py)?,))
+    }
+}
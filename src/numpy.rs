@@ -0,0 +1,60 @@
+use crate::{AsMemoryView, MemoryViewElement};
+use pyo3::{Bound, IntoPyObject, PyAny, PyErr, PyResult, Python, prelude::*};
+
+/// A zero-copy view of a Rust slice, passed to Python as a read-only numpy array.
+///
+/// ```no_run
+/// # use inline_python::{NumpyView, python};
+/// let data = vec![1.0f64, 2.0, 3.0, 4.0, 5.0, 6.0];
+/// let matrix = unsafe { NumpyView::new(&data, &[2, 3]) };
+/// python! {
+///     assert 'matrix.shape == (2, 3)
+///     assert 'matrix.dtype.name == "float64"
+///     assert 'matrix[1, 2] == 6.0
+///     assert 'matrix.sum(axis=0).tolist() == [5.0, 7.0, 9.0]
+/// }
+/// ```
+///
+/// The array is made with `numpy.asarray` from an [`AsMemoryView`] of the slice,
+/// so it points directly into the Rust slice,
+/// and the elements are laid out in C (row-major) order with the given shape.
+/// If the shape doesn't match the length of the slice, the conversion fails with a `ValueError`.
+///
+/// This only needs the `numpy` Python package when the array is created.
+/// The Rust crate does not link against numpy.
+///
+/// This requires the `numpy` feature.
+pub struct NumpyView<'a, T: MemoryViewElement> {
+    view: AsMemoryView<'a, T>,
+    shape: &'a [usize],
+}
+
+impl<'a, T: MemoryViewElement> NumpyView<'a, T> {
+    /// Wrap a slice to pass it to Python as a numpy array with the given shape.
+    ///
+    /// # Safety
+    ///
+    /// Just like for [`AsMemoryView::new`], Python has no way of knowing the lifetime of the slice.
+    /// The array, and any view or slice of it that shares its data, must not be used after the
+    /// slice is no longer borrowed.
+    /// In practice, this means the Python code may only use it within the block it is passed to.
+    /// (Operations that make a new array, like `matrix + 1` or `matrix.copy()`, are fine to keep.)
+    pub unsafe fn new(slice: &'a [T], shape: &'a [usize]) -> Self {
+        Self {
+            view: unsafe { AsMemoryView::new(slice) },
+            shape,
+        }
+    }
+}
+
+impl<'py, T: MemoryViewElement> IntoPyObject<'py> for NumpyView<'_, T> {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        py.import("numpy")?
+            .call_method1("asarray", (self.view,))?
+            .call_method1("reshape", (self.shape,))
+    }
+}
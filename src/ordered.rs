@@ -0,0 +1,52 @@
+use pyo3::{Bound, IntoPyObject, PyErr, PyResult, Python, prelude::*, types::PyDict};
+
+/// A map (or other collection of key-value pairs), passed to Python as a `dict` with sorted keys.
+///
+/// Python dictionaries remember the order in which their keys were inserted,
+/// which is the order in which Python iterates over them.
+/// A captured `HashMap` becomes a `dict` in the (arbitrary) order of the `HashMap`,
+/// which can differ between runs.
+/// Wrapping it in `Ordered` inserts the keys in sorted order instead,
+/// which makes iterating over it in Python deterministic.
+///
+/// ```
+/// # use inline_python::{Ordered, python};
+/// use std::collections::HashMap;
+///
+/// let map = HashMap::from([("b", 2), ("c", 3), ("a", 1)]);
+/// let map = Ordered::new(&map);
+/// python! {
+///     assert list('map) == ["a", "b", "c"]
+/// }
+/// ```
+///
+/// A `BTreeMap` is already sorted, so it doesn't need this wrapper.
+pub struct Ordered<M>(M);
+
+impl<M> Ordered<M> {
+    /// Wrap a map to pass it to Python with sorted keys.
+    pub fn new(map: M) -> Self {
+        Self(map)
+    }
+}
+
+impl<'py, M, K, V> IntoPyObject<'py> for Ordered<M>
+where
+    M: IntoIterator<Item = (K, V)>,
+    K: Ord + IntoPyObject<'py>,
+    V: IntoPyObject<'py>,
+{
+    type Target = PyDict;
+    type Output = Bound<'py, PyDict>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let mut items = self.0.into_iter().collect::<Vec<_>>();
+        items.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let dict = PyDict::new(py);
+        for (key, value) in items {
+            dict.set_item(key, value)?;
+        }
+        Ok(dict)
+    }
+}
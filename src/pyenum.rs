@@ -0,0 +1,130 @@
+use pyo3::{
+    Bound, FromPyObject, IntoPyObject, Py, PyAny, PyErr, PyResult, Python,
+    exceptions::{PyTypeError, PyValueError},
+    prelude::*,
+};
+use std::{any::TypeId, collections::BTreeMap, sync::Mutex};
+
+/// A Rust enum that can be passed to Python as an `enum.Enum` member, using [`PyEnum`].
+///
+/// ```
+/// use inline_python::IntoPyEnum;
+///
+/// #[derive(Clone, Copy, PartialEq, Debug)]
+/// enum Color {
+///     Red,
+///     Green,
+/// }
+///
+/// impl IntoPyEnum for Color {
+///     const NAME: &'static str = "Color";
+///     const MEMBERS: &'static [(&'static str, Self)] = &[("Red", Color::Red), ("Green", Color::Green)];
+/// }
+/// ```
+pub trait IntoPyEnum: Copy + PartialEq + 'static {
+    /// The name of the Python `Enum` class.
+    const NAME: &'static str;
+    /// The name of every member, and the Rust value it corresponds to.
+    const MEMBERS: &'static [(&'static str, Self)];
+}
+
+/// A Rust enum value, passed to Python as a member of an `enum.Enum` class.
+///
+/// The class is created the first time a value of the type is converted,
+/// with the members listed in the [`IntoPyEnum`] implementation, in that order.
+/// All values of the same type are members of the same class,
+/// so they can be compared like any Python enum.
+///
+/// Converting back to Rust looks up the member by name.
+/// That fails with a `TypeError` if the Python value is not an `enum.Enum` member,
+/// or a `ValueError` if none of the Rust values have that name.
+///
+/// ```
+/// # use inline_python::{Context, IntoPyEnum, PyEnum, python};
+/// # #[derive(Clone, Copy, PartialEq, Debug)]
+/// # enum Color { Red, Green }
+/// # impl IntoPyEnum for Color {
+/// #     const NAME: &'static str = "Color";
+/// #     const MEMBERS: &'static [(&'static str, Self)] = &[("Red", Color::Red), ("Green", Color::Green)];
+/// # }
+/// let red = PyEnum(Color::Red);
+/// let c: Context = python! {
+///     Color = type('red)
+///     assert 'red is Color.Red
+///     assert 'red.name == "Red"
+///     assert [c.name for c in Color] == ["Red", "Green"]
+///     other = Color.Green
+/// };
+/// assert_eq!(c.get::<PyEnum<Color>>("other").0, Color::Green);
+/// ```
+pub struct PyEnum<T>(pub T);
+
+/// The Python class for every [`IntoPyEnum`] type, by its `TypeId`.
+static CLASSES: Mutex<BTreeMap<TypeId, Py<PyAny>>> = Mutex::new(BTreeMap::new());
+
+fn enum_class<T: IntoPyEnum>(py: Python) -> PyResult<Bound<PyAny>> {
+    let id = TypeId::of::<T>();
+    if let Some(class) = CLASSES.lock().unwrap().get(&id) {
+        return Ok(class.bind(py).clone());
+    }
+    // The lock is not held while creating the class, since running Python code
+    // can release the GIL, which could deadlock with a thread waiting on the lock.
+    let names: Vec<&str> = T::MEMBERS.iter().map(|&(name, _)| name).collect();
+    let class = py
+        .import("enum")?
+        .getattr("Enum")?
+        .call1((T::NAME, names))?;
+    let mut classes = CLASSES.lock().unwrap();
+    Ok(classes
+        .entry(id)
+        .or_insert_with(|| class.unbind())
+        .bind(py)
+        .clone())
+}
+
+impl<'py, T: IntoPyEnum> IntoPyObject<'py> for PyEnum<T> {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let Some(&(name, _)) = T::MEMBERS.iter().find(|(_, value)| *value == self.0) else {
+            return Err(PyValueError::new_err(format!(
+                "`{}` has no Python name for this value",
+                T::NAME
+            )));
+        };
+        enum_class::<T>(py)?.getattr(name)
+    }
+}
+
+impl<'py, T: IntoPyEnum> IntoPyObject<'py> for &PyEnum<T> {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        PyEnum(self.0).into_pyobject(py)
+    }
+}
+
+impl<T: IntoPyEnum> FromPyObject<'_> for PyEnum<T> {
+    fn extract_bound(ob: &Bound<PyAny>) -> PyResult<Self> {
+        let py = ob.py();
+        if !ob.is_instance(&py.import("enum")?.getattr("Enum")?)? {
+            return Err(PyTypeError::new_err(format!(
+                "expected a `{}` enum member, got `{}`",
+                T::NAME,
+                ob.get_type().name()?
+            )));
+        }
+        let name: String = ob.getattr("name")?.extract()?;
+        match T::MEMBERS.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, value)) => Ok(PyEnum(value)),
+            None => Err(PyValueError::new_err(format!(
+                "`{}` has no member named `{name}`",
+                T::NAME
+            ))),
+        }
+    }
+}
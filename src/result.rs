@@ -0,0 +1,59 @@
+use pyo3::{
+    Bound, IntoPyObject, IntoPyObjectExt, PyAny, PyErr, PyResult, Python,
+    exceptions::PyRuntimeError,
+};
+use std::fmt::Display;
+
+/// A Rust `Result`, passed to Python as either its value or an exception.
+///
+/// `Ok(v)` becomes `v`, and `Err(e)` becomes a `RuntimeError` with the
+/// `Display` output of `e` as its message.
+/// The exception is not raised (the block would fail before the Python code could react to it),
+/// but passed as a value, such that the Python code can check for it or raise it:
+///
+/// ```
+/// # use inline_python::{PyResultValue, python};
+/// let good = PyResultValue::new("12".parse::<i32>());
+/// let bad = PyResultValue::new("x".parse::<i32>());
+/// python! {
+///     assert 'good == 12
+///     assert isinstance('bad, RuntimeError)
+///     assert str('bad) == "invalid digit found in string"
+///     try:
+///         raise 'bad
+///     except RuntimeError:
+///         pass
+/// }
+/// ```
+///
+/// For the other direction, use [`Context::try_get`](crate::Context::try_get) or
+/// [`Context::eval`](crate::Context::eval), which return a [`PyResult`]
+/// with the Python exception as the error.
+pub struct PyResultValue<T, E>(Result<T, E>);
+
+impl<T, E> PyResultValue<T, E> {
+    /// Wrap a `Result` to pass it to Python as its value or an exception.
+    pub fn new(result: Result<T, E>) -> Self {
+        Self(result)
+    }
+}
+
+impl<'py, T, E> IntoPyObject<'py> for PyResultValue<T, E>
+where
+    T: IntoPyObject<'py>,
+    E: Display,
+{
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        match self.0 {
+            Ok(value) => value.into_bound_py_any(py),
+            Err(e) => Ok(PyRuntimeError::new_err(e.to_string())
+                .into_value(py)
+                .into_bound(py)
+                .into_any()),
+        }
+    }
+}
@@ -1,22 +1,87 @@
 use crate::Context;
-use pyo3::{Bound, PyObject, PyResult, Python, ffi, types::PyAny};
+use pyo3::{
+    Bound, PyErr, PyObject, PyResult, Python, ffi,
+    prelude::*,
+    types::{PyAny, PyBytes, PyTuple},
+};
 
+/// The compiled code of a `python!{}` block.
+#[doc(hidden)]
+pub struct Code {
+    /// The marshalled code object(s), see [`run_python_code`].
+    pub bytecode: &'static [u8],
+    /// The Rust source file that contains the block.
+    pub file: &'static str,
+    /// The line and column of the first Rust token of every line of the block.
+    pub lines: &'static [(u32, u32)],
+}
+
+impl Code {
+    /// Find the Rust line and column of the innermost frame of the traceback that is within this block.
+    pub(crate) fn error_location(&self, py: Python, err: &PyErr) -> Option<(u32, u32)> {
+        let code = py
+            .import("marshal")
+            .ok()?
+            .call_method1("loads", (PyBytes::new(py, self.bytecode),))
+            .ok()?;
+        let code = match code.downcast::<PyTuple>() {
+            Ok(tuple) => tuple.get_item(0).ok()?,
+            Err(_) => code,
+        };
+        let filename = code.getattr("co_filename").ok()?;
+        let mut line = None;
+        let mut tb = err.traceback(py).map(|tb| tb.into_any());
+        while let Some(t) = tb.filter(|t| !t.is_none()) {
+            let file = t
+                .getattr("tb_frame")
+                .ok()?
+                .getattr("f_code")
+                .ok()?
+                .getattr("co_filename")
+                .ok()?;
+            if file.eq(&filename).ok()? {
+                line = Some(t.getattr("tb_lineno").ok()?.extract::<u32>().ok()?);
+            }
+            tb = Some(t.getattr("tb_next").ok()?);
+        }
+        let line = line?;
+        let &(_, column) = self.lines.iter().find(|&&(l, _)| l == line)?;
+        Some((line, column))
+    }
+}
+
+/// Run the marshalled code, and return the value of the final expression (or `None`).
+///
+/// The bytecode is either a single code object, or a tuple of two code objects:
+/// one for the statements and one for the final expression.
 pub fn run_python_code<'p>(
     py: Python<'p>,
     context: &Context,
     bytecode: &[u8],
 ) -> PyResult<Bound<'p, PyAny>> {
-    unsafe {
+    if cfg!(inline_python_validate_only) {
+        return Ok(py.None().into_bound(py));
+    }
+    let code = unsafe {
         let ptr = ffi::PyMarshal_ReadObjectFromString(
             bytecode.as_ptr() as *const _,
             bytecode.len() as isize,
         );
-        let code = PyObject::from_owned_ptr_or_err(py, ptr)?;
+        PyObject::from_owned_ptr_or_err(py, ptr)?
+    };
+    let eval = |code: &Bound<PyAny>| unsafe {
         let result = ffi::PyEval_EvalCode(
             code.as_ptr(),
             context.globals.as_ptr(),
             std::ptr::null_mut(),
         );
         Bound::from_owned_ptr_or_err(py, result)
+    };
+    match code.bind(py).downcast::<PyTuple>() {
+        Ok(tuple) => {
+            eval(&tuple.get_item(0)?)?;
+            eval(&tuple.get_item(1)?)
+        }
+        Err(_) => eval(code.bind(py)),
     }
 }
@@ -0,0 +1,30 @@
+use pyo3::{Bound, IntoPyObject, PyErr, PyResult, Python, types::PyTuple};
+
+/// A Python tuple of any length, made by [`py_tuple!`](crate::py_tuple).
+///
+/// PyO3 only converts Rust tuples of up to twelve elements to and from Python.
+/// This holds the values of a longer tuple until it is converted.
+pub struct PyTupleOf<F>(F);
+
+impl<F> PyTupleOf<F>
+where
+    F: for<'py> FnOnce(Python<'py>) -> PyResult<Bound<'py, PyTuple>>,
+{
+    #[doc(hidden)]
+    pub fn new(f: F) -> Self {
+        Self(f)
+    }
+}
+
+impl<'py, F> IntoPyObject<'py> for PyTupleOf<F>
+where
+    F: for<'p> FnOnce(Python<'p>) -> PyResult<Bound<'p, PyTuple>>,
+{
+    type Target = PyTuple;
+    type Output = Bound<'py, PyTuple>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyTuple>> {
+        (self.0)(py)
+    }
+}
@@ -0,0 +1,388 @@
+use inline_python::{
+    AsMemoryView, Context, IntoPyEnum, NamedTuple, Ordered, PyCallback, PyEnum, PyIter,
+    PyResultValue, py_tuple, python,
+};
+
+#[test]
+fn capture_expression() {
+    struct Config {
+        width: i32,
+    }
+    let config = Config { width: 3 };
+    let width = 10;
+    python! {
+        assert '_(config.width) == 3
+        assert '_((config.width + 1) * 2) == 8
+        assert 'width == 10
+    }
+}
+
+#[test]
+fn capture_many_expressions_in_order() {
+    let mut order = Vec::new();
+    let mut g = |i: i32| {
+        order.push(i);
+        i
+    };
+    python! {
+        assert ['_(g(0)), '_(g(1)), '_(g(2)), '_(g(3)), '_(g(4)), '_(g(5)),
+                '_(g(6)), '_(g(7)), '_(g(8)), '_(g(9)), '_(g(10)), '_(g(11))] == list(range(12))
+    }
+    assert_eq!(order, (0..12).collect::<Vec<_>>());
+}
+
+#[test]
+fn capture_in_format_string() {
+    let x = 42;
+    let width = 6;
+    let name = "Rust";
+    python! {
+        d = {"key": 1}
+        assert f "value is {'x}" == "value is 42"
+        assert f "{'x:>5}|{'x:<{'width}}|" == "   42|42    |"
+        assert f "{{'x}} {'name!r} {d['key']}" == "{'x} 'Rust' 1"
+        assert f "{ {'x: 'name}['x] }" == "Rust"
+        assert "{'x}" == "{" + "'x}"
+    }
+}
+
+#[test]
+fn capture_callback() {
+    use std::sync::{Arc, Mutex};
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let log2 = log.clone();
+    let push = PyCallback::new(move |s: String| log2.lock().unwrap().push(s));
+    let answer = PyCallback::new(|| 42);
+    let c = Context::new();
+    c.run(python! {
+        callback = 'push
+        callback("a")
+        assert 'answer() == 42
+        try:
+            callback(1)
+            assert False
+        except TypeError:
+            pass
+        try:
+            callback("a", "b")
+            assert False
+        except TypeError:
+            pass
+    });
+    c.run(python! {
+        callback("b")
+    });
+    assert_eq!(*log.lock().unwrap(), ["a", "b"]);
+}
+
+#[test]
+fn capture_memoryview() {
+    let bytes = b"hello".to_vec();
+    let ints = [1i32, -2, 3];
+    python! {
+        view = '_(unsafe { AsMemoryView::new(&bytes) })
+        assert view.readonly
+        assert bytes(view) == b"hello"
+        view = '_(unsafe { AsMemoryView::new(&ints) })
+        assert view.format == "i"
+        assert view.itemsize == 4
+        assert view.shape == (3,)
+        assert view.tolist() == [1, -2, 3]
+        view.release()
+    }
+}
+
+#[test]
+fn capture_mut() {
+    let mut n = 1;
+    let mut v = vec![1, 2];
+    python! {
+        'mut n += 1
+        'mut v.append('n)
+    }
+    assert_eq!(n, 2);
+    assert_eq!(v, [1, 2, 2]);
+}
+
+#[test]
+fn capture_mut_incompatible() {
+    let c = Context::new();
+    let mut n = 1;
+    let err = c
+        .run_checked(python! {
+            'mut n = "not a number"
+        })
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Unable to convert `'mut n` back to `i32`")
+    );
+    assert_eq!(n, 1);
+}
+
+#[test]
+fn capture_ref() {
+    let v = vec![1, 2, 3];
+    let s = String::from("hello");
+    python! {
+        assert 'ref v == [1, 2, 3]
+        assert 'ref s == "hello"
+        assert 's == "hello" // Also captured by reference.
+    }
+    assert_eq!(v, [1, 2, 3]);
+    assert_eq!(s, "hello");
+}
+
+#[test]
+fn capture_iterator() {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    static PRODUCED: AtomicUsize = AtomicUsize::new(0);
+    let numbers = PyIter::new((1..).inspect(|_| {
+        PRODUCED.fetch_add(1, Relaxed);
+    }));
+    let words = PyIter::new(vec![String::from("a"), String::from("b")]);
+    python! {
+        it = 'numbers
+        assert next(it) == 1
+        assert next(it) == 2
+        assert list('words) == ["a", "b"]
+    }
+    assert_eq!(PRODUCED.load(Relaxed), 2);
+}
+
+#[test]
+fn capture_bytes() {
+    let vec = b"vec".to_vec();
+    let slice: &[u8] = b"slice";
+    let c: Context = python! {
+        assert type('vec) is bytes and 'vec == b"vec"
+        assert type('slice) is bytes and 'slice == b"slice"
+        data = 'vec + 'slice
+        array = bytearray(data)
+    };
+    assert_eq!(c.get_bytes("data"), b"vecslice");
+    assert_eq!(c.get_bytes("array"), b"vecslice");
+    assert_eq!(c.get::<Vec<u8>>("data"), b"vecslice");
+}
+
+#[test]
+fn capture_ordered_map() {
+    let map = (0..100)
+        .map(|i| (format!("{i:02}"), i))
+        .collect::<std::collections::HashMap<_, _>>();
+    let ordered = Ordered::new(map);
+    python! {
+        assert list('ordered.values()) == list(range(100))
+    }
+}
+
+#[test]
+fn capture_named_tuple() {
+    let points = [(1, 2), (3, 4)].map(|p| NamedTuple::new("Point", &["x", "y"], p));
+    let wrong = NamedTuple::new("Point", &["x"], (1, 2));
+    let c = Context::new();
+    assert!(c.try_set("wrong", wrong).is_err());
+    python! {
+        assert [p.x + p.y for p in 'points] == [3, 7]
+        assert 'points[1]._fields == ("x", "y")
+        x, y = 'points[0]
+        assert (x, y) == (1, 2)
+    }
+}
+
+#[test]
+fn capture_path() {
+    use std::path::{Path, PathBuf};
+    let dir = PathBuf::from("/tmp/dir");
+    let file = Path::new("file.txt");
+    let mut out = PathBuf::new();
+    let c: Context = python! {
+        import pathlib
+        assert isinstance('ref dir, pathlib.Path)
+        joined = 'ref dir.joinpath('file)
+        assert joined.name == "file.txt"
+        'mut out = joined.with_suffix(".rs")
+        as_str = str(joined)
+    };
+    assert_eq!(c.get::<PathBuf>("joined"), dir.join(file));
+    assert_eq!(c.get::<PathBuf>("as_str"), dir.join(file));
+    assert_eq!(out, Path::new("/tmp/dir/file.rs"));
+}
+
+#[test]
+fn capture_enum() {
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Op {
+        Add,
+        Sub,
+    }
+    impl IntoPyEnum for Op {
+        const NAME: &'static str = "Op";
+        const MEMBERS: &'static [(&'static str, Self)] = &[("Add", Op::Add), ("Sub", Op::Sub)];
+    }
+
+    let a = PyEnum(Op::Add);
+    let b = PyEnum(Op::Sub);
+    let mut c = PyEnum(Op::Add);
+    let ctx: Context = python! {
+        import enum
+        assert type('a) is type('b)
+        assert 'a != 'b
+        'mut c = type('a).Sub
+        other = enum.Enum("Other", ["X"]).X
+    };
+    assert_eq!(c.0, Op::Sub);
+    assert!(ctx.try_get::<PyEnum<Op>>("other").is_err());
+}
+
+#[test]
+fn capture_large_integers() {
+    let min = i128::MIN;
+    let max = u128::MAX;
+    let c: Context = python! {
+        assert 'min == -2**127
+        assert 'max == 2**128 - 1
+        too_big = 'max + 1
+        mid = 2**100
+    };
+    assert_eq!(c.get::<i128>("mid"), 1 << 100);
+    let err = c.try_get::<u128>("too_big").unwrap_err();
+    assert!(err.to_string().starts_with("OverflowError"), "{err}");
+}
+
+#[cfg(feature = "num-bigint")]
+#[test]
+fn capture_bigint() {
+    use num_bigint::BigInt;
+    let big = BigInt::from(u128::MAX) * 3u32;
+    let c: Context = python! {
+        assert 'ref big == (2**128 - 1) * 3
+        x = -'ref big
+    };
+    assert_eq!(c.get::<BigInt>("x"), -big);
+}
+
+#[test]
+fn capture_option() {
+    let some = Some(1);
+    let none = None::<i32>;
+    let nested = Some(vec![Some("a".to_string()), None]);
+    let c: Context = python! {
+        assert 'some == 1
+        assert 'none is None
+        assert 'nested == ["a", None]
+        nested = 'nested + [None, "b"]
+        missing = None
+    };
+    assert_eq!(c.get::<Option<i32>>("missing"), None);
+    assert_eq!(
+        c.get::<Option<Vec<Option<String>>>>("nested"),
+        Some(vec![Some("a".into()), None, None, Some("b".into())]),
+    );
+    assert_eq!(c.get::<Option<Vec<Option<String>>>>("missing"), None);
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn capture_datetime() {
+    use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+    let time = Utc.with_ymd_and_hms(2024, 2, 29, 12, 30, 0).unwrap();
+    let date = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+    let c: Context = python! {
+        import datetime
+        assert 'time == datetime.datetime(2024, 2, 29, 12, 30, tzinfo=datetime.timezone.utc)
+        assert 'date == datetime.date(2000, 1, 1)
+        later = 'time + datetime.timedelta(days=1)
+        naive = later.replace(tzinfo=None)
+    };
+    assert_eq!(
+        c.get::<DateTime<Utc>>("later"),
+        time + chrono::Duration::days(1)
+    );
+    assert_eq!(
+        c.get::<chrono::NaiveDateTime>("naive"),
+        (time + chrono::Duration::days(1)).naive_utc()
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn capture_json_value() {
+    use inline_python::JsonValue;
+    use serde_json::json;
+    let value = JsonValue(json!({"a": [1, -2, 2.5, "x", null, true], "b": {}, "big": u64::MAX}));
+    let c: Context = python! {
+        assert 'ref value == {"a": [1, -2, 2.5, "x", None, True], "b": {}, "big": 2**64 - 1}
+        back = 'ref value
+        not_json = [1, {1: 2}]
+        nan = float("nan")
+        huge = 2**64
+    };
+    assert_eq!(c.get::<JsonValue>("back"), value);
+    for name in ["not_json", "nan", "huge"] {
+        assert!(c.try_get::<JsonValue>(name).is_err());
+    }
+}
+
+#[test]
+fn capture_duration() {
+    use std::time::Duration;
+    let timeout = Duration::from_millis(1500);
+    let c: Context = python! {
+        import datetime
+        assert 'timeout == datetime.timedelta(seconds=1.5)
+        doubled = 'timeout * 2
+        negative = -'timeout
+    };
+    assert_eq!(c.get::<Duration>("doubled"), Duration::from_secs(3));
+    assert!(c.try_get::<Duration>("negative").is_err());
+}
+
+#[test]
+fn capture_long_tuple() {
+    // Twelve elements is the longest tuple PyO3 converts.
+    let t = (1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);
+    let c: Context = python! {
+        assert 't == tuple(range(1, 13))
+        back = 't
+    };
+    assert_eq!(
+        c.get::<(i32, i32, i32, i32, i32, i32, i32, i32, i32, i32, i32, i32)>("back"),
+        t
+    );
+
+    let name = String::from("thirteen");
+    let t = py_tuple!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, name, vec![14], (15,));
+    python! {
+        assert 't == tuple(range(1, 13)) + ("thirteen", [14], (15,))
+    }
+    let empty = py_tuple!();
+    python! {
+        assert 'empty == ()
+    }
+}
+
+#[test]
+fn capture_result() {
+    let ok: Result<Vec<i32>, String> = Ok(vec![1, 2]);
+    let err: Result<Vec<i32>, String> = Err("no data".to_string());
+    let ok = PyResultValue::new(ok);
+    let err = PyResultValue::new(err);
+    python! {
+        assert 'ok == [1, 2]
+        assert type('err) is RuntimeError
+        assert 'err.args == ("no data",)
+    }
+}
+
+#[cfg(feature = "num-complex")]
+#[test]
+fn capture_complex() {
+    use num_complex::Complex64;
+    let z = Complex64::new(1.5, -2.0);
+    let c: Context = python! {
+        assert 'z == complex(1.5, -2)
+        w = 'z * 1j
+    };
+    assert_eq!(c.get::<Complex64>("w"), Complex64::new(2.0, 1.5));
+}
@@ -0,0 +1,60 @@
+use inline_python::{Context, python};
+
+#[test]
+fn structured_exception() {
+    let c = Context::new();
+    let err = c
+        .run_with_exception(python! {
+            def check(x):
+                if x < 0:
+                    raise ValueError("negative", x)
+            check(-1)
+        })
+        .unwrap_err();
+    assert_eq!(err.type_name, "ValueError");
+    assert_eq!(err.message, "('negative', -1)");
+    assert_eq!(err.args, ["'negative'", "-1"]);
+    let frames: Vec<_> = err
+        .traceback
+        .iter()
+        .map(|f| (f.line, f.name.as_str()))
+        .collect();
+    assert_eq!(frames, [(11, "<module>"), (10, "check")]);
+    assert!(err.traceback.iter().all(|f| f.file.ends_with("error.rs")));
+    assert_eq!(err.to_string(), "ValueError: ('negative', -1)");
+}
+
+#[test]
+fn exception_without_usable_args() {
+    let c = Context::new();
+    let err = c
+        .run_with_exception(python! {
+            class Odd(Exception):
+                args = property(lambda self: 1 / 0)
+            raise Odd("odd")
+        })
+        .unwrap_err();
+    assert_eq!(err.type_name, "Odd");
+    assert_eq!(err.message, "odd");
+    assert_eq!(err.to_string(), "Odd: odd");
+}
+
+#[test]
+fn panic_points_at_rust_source() {
+    let c = Context::new();
+    let line = line!();
+    let block = python! {
+        //! module: renamed
+        def check(x):
+            if x < 0:
+                raise ValueError("negative")
+        check(-1)
+    };
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| c.run(block))).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+    assert!(msg.contains("ValueError: negative"), "{msg}");
+    assert!(
+        msg.ends_with(&format!("  --> {}:{}:17", file!(), line + 5)),
+        "{msg}"
+    );
+}
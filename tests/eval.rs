@@ -0,0 +1,112 @@
+use inline_python::{Context, python, python_eval};
+
+#[test]
+fn eval_expression() {
+    let x: i32 = python_eval! { 1 + 2 };
+    assert_eq!(x, 3);
+}
+
+#[test]
+fn eval_statements_and_expression() {
+    let n = 4;
+    let s: String = python_eval! {
+        words = ["a"] * 'n
+        "-".join(words)
+    };
+    assert_eq!(s, "a-a-a-a");
+}
+
+#[test]
+fn eval_in_context() {
+    let c = Context::new();
+    c.run(python! {
+        foo = 5
+    });
+    let x: i32 = c
+        .eval(python! {
+            foo + 1
+        })
+        .unwrap();
+    assert_eq!(x, 6);
+    let none: Option<i32> = c
+        .eval(python! {
+            bar = 1
+        })
+        .unwrap();
+    assert_eq!(none, None);
+    assert!(
+        c.eval::<i32>(python! {
+            raise ValueError()
+        })
+        .is_err()
+    );
+}
+
+#[test]
+fn compile_modes() {
+    let x: i32 = python_eval! {
+        //! mode: eval
+        1 + 2
+    };
+    assert_eq!(x, 3);
+
+    let c: Context = python! {
+        //! mode: single
+        import sys
+        shown = []
+        sys.displayhook = lambda value: shown.append(str(value))
+        1 + 2
+        "hello"
+    };
+    assert_eq!(c.get::<Vec<String>>("shown"), ["3", "hello"]);
+}
+
+#[test]
+fn module_name() {
+    let c: Context = python! {
+        //! module: mymod
+        import sys
+        in_main = __name__ == "__main__"
+        filename = sys._getframe().f_code.co_filename
+    };
+    assert!(!c.get::<bool>("in_main"));
+    assert_eq!(c.get::<String>("__name__"), "mymod");
+    assert_eq!(c.get::<String>("filename"), "mymod");
+
+    let name: String = python_eval! {
+        //! mode: eval
+        //! module: other
+        __name__
+    };
+    assert_eq!(name, "other");
+}
+
+#[test]
+fn random_seed() {
+    let a: Vec<f64> = python_eval! {
+        //! seed: 42
+        import random
+        [random.random() for _ in range(3)]
+    };
+    let b: Vec<f64> = python_eval! {
+        //! seed: 42
+        import random
+        [random.random() for _ in range(3)]
+    };
+    assert_eq!(a, b);
+}
+
+#[test]
+fn optimization_level() {
+    let debug: bool = python_eval! {
+        __debug__
+    };
+    assert!(debug);
+    let doc: Option<String> = python_eval! {
+        //! optimize: 2
+        def f():
+            "docstring"
+        f.__doc__
+    };
+    assert_eq!(doc, None);
+}
@@ -0,0 +1,62 @@
+use inline_python::{PyCallback, python_body, python_fn};
+
+#[test]
+fn call_function() {
+    let f = python_fn! {
+        def divide(a, b = 2):
+            return a #/ b if isinstance(a, int) else a / b
+    };
+    assert_eq!(f.call::<i32>((7,)).unwrap(), 3);
+    assert_eq!(f.call::<i32>((7, 3)).unwrap(), 2);
+    assert_eq!(f.call::<f64>((1.0, 4)).unwrap(), 0.25);
+    assert!(f.call::<i32>((1, 0)).is_err());
+    assert!(f.call::<i32>(()).is_err());
+}
+
+#[python_body]
+fn add(a: i32, b: i32) -> i32 {
+    return a + b;
+}
+
+#[python_body]
+fn apply<F: Fn(i32) -> i32 + Send + 'static>(f: PyCallback<F, (i32,)>, x: i32) -> i32 {
+    python! {
+        return f(x) * 2
+    }
+}
+
+#[python_body]
+fn hypot(x: f64, y: f64) -> f64 {
+    python! {
+        return (x ** 2 + y ** 2) ** 0.5
+    }
+}
+
+#[python_body]
+fn join_words(words: Vec<String>, _: u8, r#type: &str) -> String {
+    python! {
+        words.append(type)
+        if not words:
+            return ""
+        return " ".join(words)
+    }
+}
+
+#[python_body]
+fn check_positive(n: i32) {
+    python! {
+        if n <= 0:
+            raise ValueError("not positive")
+    }
+}
+
+#[test]
+fn python_body_function() {
+    assert_eq!(add(2, 3), 5);
+    assert_eq!(apply(PyCallback::new(|x: i32| x + 1), 20), 42);
+    assert_eq!(hypot(3.0, 4.0), 5.0);
+    let words = vec!["a".to_string(), "b".to_string()];
+    assert_eq!(join_words(words, 0, "c"), "a b c");
+    check_positive(1);
+    assert!(std::panic::catch_unwind(|| check_positive(0)).is_err());
+}
@@ -21,3 +21,340 @@ fn extract_global() {
 
     assert_eq!(c.get::<i32>("foo"), 5);
 }
+
+#[test]
+fn extract_result() {
+    let c = inline_python::Context::new();
+    assert_eq!(c.result::<i32>(), None);
+
+    c.run(python! {
+        __result__ = 5
+    });
+
+    assert_eq!(c.result::<i32>(), Some(5));
+    assert!(!c.keys().contains(&"__result__".to_string()));
+}
+
+#[test]
+fn set_global() {
+    let c = inline_python::Context::new();
+
+    c.set("foo", 5);
+    c.set("foo", 6);
+
+    c.run(python! {
+        assert foo == 6
+    });
+
+    assert!(c.try_set("not valid", 1).is_err());
+    assert!(c.try_set("class", 1).is_err());
+}
+
+#[test]
+fn introspect_globals() {
+    let c = inline_python::Context::new();
+
+    c.run(python! {
+        foo = 5
+        bar = "bar"
+    });
+
+    assert!(c.contains("foo"));
+    assert!(!c.contains("baz"));
+    let mut keys = c.keys();
+    keys.sort();
+    assert_eq!(keys, ["bar", "foo"]);
+    assert!(c.keys_all().iter().any(|k| k == "__builtins__"));
+}
+
+#[test]
+fn context_from_existing_dict() {
+    use inline_python::pyo3::{prelude::*, types::PyDict};
+
+    let (a, b, globals) = Python::with_gil(|py| {
+        let globals = PyDict::new(py);
+        (
+            inline_python::Context::from_bound_globals(&globals),
+            inline_python::Context::from_globals(globals.clone().unbind()),
+            globals.unbind(),
+        )
+    });
+
+    a.run(python! {
+        x = len("abc")
+    });
+    b.run(python! {
+        assert x == 3
+        y = 2
+    });
+
+    assert_eq!(a.get::<i32>("y"), 2);
+    Python::with_gil(|py| {
+        let globals = globals.bind(py);
+        assert_eq!(
+            globals
+                .get_item("x")
+                .unwrap()
+                .unwrap()
+                .extract::<i32>()
+                .unwrap(),
+            3
+        );
+    });
+}
+
+#[test]
+fn extract_multiple_globals() {
+    let c = inline_python::Context::new();
+
+    c.run(python! {
+        a = 1
+        b = 2
+        c = "three"
+    });
+
+    assert_eq!(c.get_all::<i32>(&["a", "b"]).unwrap(), [1, 2]);
+    assert!(c.get_all::<i32>(&["a", "c"]).is_err());
+    assert!(c.try_get::<i32>("d").is_err());
+    assert_eq!(
+        c.get_tuple::<(i32, String)>(&["b", "c"]).unwrap(),
+        (2, "three".to_string())
+    );
+}
+
+#[test]
+fn extract_global_with_default() {
+    let c = inline_python::Context::new();
+
+    c.run(python! {
+        a = 1
+        b = "two"
+    });
+
+    assert_eq!(c.get_or("a", 0), 1);
+    assert_eq!(c.get_or("b", 0), 0);
+    assert_eq!(c.get_or("c", 0), 0);
+    assert_eq!(c.get_or_else("b", || "default".to_string()), "two");
+    assert_eq!(c.get_or_default::<Vec<i32>>("c"), Vec::<i32>::new());
+    let missing = c.try_get::<i32>("c").unwrap_err();
+    let wrong_type = c.try_get::<i32>("b").unwrap_err();
+    inline_python::pyo3::Python::with_gil(|py| {
+        assert!(missing.is_instance_of::<inline_python::pyo3::exceptions::PyNameError>(py));
+        assert!(wrong_type.is_instance_of::<inline_python::pyo3::exceptions::PyTypeError>(py));
+    });
+}
+
+#[test]
+fn update_from_other_context() {
+    let a: inline_python::Context = python! {
+        def double(x):
+            return x * 2
+        value = 1
+    };
+    let b: inline_python::Context = python! {
+        value = 21
+    };
+
+    a.update_from(&b);
+
+    a.run(python! {
+        assert double(value) == 42
+        assert __name__ == "__main__"
+    });
+}
+
+#[test]
+fn move_to_other_thread() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<inline_python::Context>();
+
+    let c: inline_python::Context = python! {
+        import threading
+        main_thread = threading.get_ident()
+    };
+    let c = std::thread::spawn(move || {
+        c.run(python! {
+            assert threading.get_ident() != main_thread
+            x = 1
+        });
+        c
+    })
+    .join()
+    .unwrap();
+    assert_eq!(c.get::<i32>("x"), 1);
+}
+
+#[test]
+fn other_threads_run_during_block() {
+    let c = inline_python::Context::new();
+    c.set("done", false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            c.run(python! {
+                import time
+                deadline = time.monotonic() + 10
+                while not done:
+                    assert time.monotonic() < deadline
+            })
+        });
+        // This needs the GIL, while the block above is still running.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        c.set("done", true);
+    });
+}
+
+#[test]
+fn run_source_code() {
+    let c = inline_python::Context::new();
+    c.run(python! {
+        x = 5
+    });
+    c.run_source("y = x + 1\ndef f(a):\n    return a * 2")
+        .unwrap();
+    assert_eq!(c.get::<i32>("y"), 6);
+    assert_eq!(c.eval_source::<i32>("f(y)").unwrap(), 12);
+    assert!(c.run_source("1 +").is_err());
+    assert!(c.eval_source::<i32>("undefined").is_err());
+}
+
+#[test]
+fn import_module() {
+    let c = inline_python::Context::new();
+    c.import("json", None).unwrap();
+    c.import("os.path", Some("osp")).unwrap();
+    assert_eq!(c.keys(), ["json", "osp"]);
+    c.run(python! {
+        assert json.dumps([1]) == "[1]"
+        assert osp.basename("a/b") == "b"
+    });
+    assert!(c.import("no_such_module", None).is_err());
+    assert!(c.import("json", Some("not valid")).is_err());
+}
+
+#[test]
+fn remove_globals() {
+    let c = inline_python::Context::new();
+    c.set("x", 1);
+    assert!(c.remove("x"));
+    assert!(!c.remove("x"));
+    assert!(!c.remove("__builtins__"));
+    assert!(c.contains("__builtins__"));
+    assert!(c.remove_all("__builtins__"));
+    assert!(!c.contains("__builtins__"));
+}
+
+#[test]
+fn run_in_context() {
+    let contexts = [inline_python::Context::new(), inline_python::Context::new()];
+    let x = 3;
+    python! {
+        in contexts[1];
+        y = 'x * 2
+    }
+    assert!(!contexts[0].contains("y"));
+    let z: i32 = inline_python::python_eval! {
+        in contexts[1];
+        y + 1
+    };
+    assert_eq!(z, 7);
+}
+
+#[test]
+fn python_scope() {
+    let c = inline_python::python_scope! {
+        {
+            x = 1
+        };
+        {
+            x += 1
+        }
+    };
+    assert_eq!(c.get::<i32>("x"), 2);
+
+    let ran_second = std::panic::catch_unwind(|| {
+        inline_python::python_scope! {
+            {
+                raise ValueError("first")
+            }
+            {
+                import sys
+                sys._second_block_ran = True
+            }
+        };
+    });
+    assert!(ran_second.is_err());
+    let ran: bool = inline_python::python_eval! {
+        import sys
+        hasattr(sys, "_second_block_ran")
+    };
+    assert!(!ran);
+}
+
+#[test]
+fn shared_main_globals() {
+    let c = inline_python::Context::new_shared();
+    c.run(python! {
+        _shared_main_globals_test = 1
+    });
+    inline_python::Context::new_shared().run(python! {
+        import __main__
+        assert __main__._shared_main_globals_test == 1
+        del _shared_main_globals_test
+    });
+    assert!(!c.contains("_shared_main_globals_test"));
+}
+
+#[test]
+fn compile_once_run_many() {
+    let c = inline_python::Context::new();
+    let count = inline_python::Context::compile("n = globals().get('n', 0) + 1").unwrap();
+    let double = inline_python::Context::compile("n * 2").unwrap();
+    for _ in 0..3 {
+        c.run_compiled(&count).unwrap();
+    }
+    assert_eq!(c.eval_compiled::<i32>(&double).unwrap(), 6);
+    assert_eq!(c.eval_compiled::<Option<i32>>(&count).unwrap(), None);
+
+    let d = inline_python::Context::new();
+    assert!(d.run_compiled(&double).is_err());
+
+    assert!(inline_python::Context::compile("n = ").is_err());
+    assert_eq!(c.get::<i32>("n"), 4);
+}
+
+#[cfg(feature = "numpy")]
+#[test]
+fn get_buffer_as_array() {
+    let c: inline_python::Context = python! {
+        import array
+        flat = array.array("i", range(6))
+        matrix = memoryview(flat).cast("B").cast("i", [2, 3])
+        floats = array.array("d", [0.5, 1.5])
+    };
+    assert_eq!(
+        c.get_numpy::<i32>("flat"),
+        (vec![0, 1, 2, 3, 4, 5], vec![6])
+    );
+    assert_eq!(
+        c.get_numpy::<i32>("matrix"),
+        (vec![0, 1, 2, 3, 4, 5], vec![2, 3])
+    );
+    assert_eq!(c.get_numpy::<f64>("floats").0, [0.5, 1.5]);
+    let c = std::panic::AssertUnwindSafe(c);
+    assert!(std::panic::catch_unwind(|| c.get_numpy::<f64>("flat")).is_err());
+}
+
+#[test]
+fn run_through_mut_reference() {
+    fn increment(c: &mut inline_python::Context) {
+        c.run_mut(python! {
+            n += 1
+        });
+    }
+    let mut c: inline_python::Context = python! {
+        n = 0
+    };
+    increment(&mut c);
+    increment(&mut c);
+    assert_eq!(c.get::<i32>("n"), 2);
+}
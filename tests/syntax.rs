@@ -0,0 +1,156 @@
+use inline_python::python;
+
+#[test]
+fn hash_comments() {
+    python! {
+        # A comment, with 'quotes and (brackets).
+        x = 7 # A trailing comment.
+        assert x == 7
+    }
+    python! {
+    # A comment left of the code.
+        x = 1
+        if x:
+                # A comment right of the code.
+            y = 2
+    # Another comment left of the code.
+        assert y == 2
+    }
+}
+
+#[test]
+fn hash_comment_brackets_closed_on_same_line() {
+    python! {
+        x = 1 # note (a) [b] {c}
+        y = 2
+        # (d)
+        assert x + y == 3
+    }
+}
+
+#[test]
+fn single_quoted_strings() {
+    let a = 1;
+    python! {
+        assert 'a' == "a"
+        assert '\n' == "\n"
+        assert '"' + 'a' == "\"a"
+        assert 'a == 1
+    }
+}
+
+#[test]
+fn prefixed_strings() {
+    python! {
+        x = 5
+        assert f "{x}" == "5"
+        assert f r"{x}\d" == "5\\d"
+        assert "b" in "abc"
+        assert 2.0 * 5 == 10
+    }
+}
+
+#[test]
+fn unicode_escapes() {
+    python! {
+        assert "\u{e9}" == "é"
+        assert '\u{1F600}' == "😀"
+        assert f "\u{e9}{1}" == "é1"
+        assert "\\u{e9}" == "\\" + "u{e9}"
+        assert r"\u{e9}" == "\\" + "u{e9}"
+    }
+}
+
+#[test]
+fn raw_strings() {
+    python! {
+        x = 5
+        assert r#"a\"b"# == "a\\\"b"
+        assert r#"it's "quoted""# == "it" + "'" + "s \"quoted\""
+        assert r"\" == "\\"
+        assert r#"\"# + "'" == "\\'"
+        assert f r#"{x}"\d"# == "5\"\\d"
+        assert br#"\x"y"# == b"\\x\"y"
+        assert r#"a\b
+            "c""#.split() == ["a\\b", "\"c\""]
+    }
+}
+
+#[test]
+fn floor_division() {
+    python! {
+        x = 7 #/ 2
+        assert x == 3
+        x #/= 2
+        assert x == 1
+        # A comment after a space is still a comment: #/ and #/= in here are not converted.
+    }
+}
+
+#[test]
+fn decorators() {
+    python! {
+        import functools
+
+        def twice(f):
+            @functools.wraps(f)
+            def wrapper(*args):
+                return 2 * f(*args)
+            return wrapper
+
+        class A:
+            @staticmethod
+            def s():
+                return 1
+            @property
+            def p(self):
+                return 2
+            @ twice
+            @functools.lru_cache(maxsize=None)
+            def m(self, x):
+                return x
+
+        assert A.s() == 1
+        assert A().p == 2
+        assert A().m(3) == 6
+        assert A.m.__name__ == "m"
+    }
+}
+
+#[test]
+fn match_statement() {
+    let describe = |value: i32| -> String {
+        inline_python::python_eval! {
+            def describe(x):
+                match x:
+                    case 0:
+                        return "zero"
+                    case 1 | 2:
+                        return "small"
+                    case int(n) if n < 0:
+                        return "negative"
+                    case _:
+                        return "large"
+            describe('value)
+        }
+    };
+    assert_eq!(describe(0), "zero");
+    assert_eq!(describe(2), "small");
+    assert_eq!(describe(-5), "negative");
+    assert_eq!(describe(100), "large");
+
+    python! {
+        match {"kind": "point", "x": 1, "y": 2}:
+            case {"kind": "point", "x": x, "y": y}:
+                assert (x, y) == (1, 2)
+            case _:
+                assert False
+        match [1, 2, 3]:
+            case [first, *rest]:
+                assert first == 1 and rest == [2, 3]
+        # match and case are still usable as names.
+        match = 1
+        case = match + 1
+        assert case == 2
+    }
+}